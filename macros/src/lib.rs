@@ -109,18 +109,31 @@ pub fn command_handler(args: TokenStream, annotated_item: TokenStream) -> TokenS
 
     let mut state_type = None;
     let mut command_ident = None;
+    let mut connection_ident = None;
+    let mut connection_type = None;
     sig.inputs.clone().into_iter().for_each(|input| {
         if let FnArg::Typed(PatType { pat, ty, .. }) = input {
             if let Pat::Ident(ident) = *pat {
-                if let Type::Path(path) = *ty {
-                    if path.path.segments.last().unwrap().ident == "ArkeCommand" {
+                match *ty {
+                    Type::Path(path) if path.path.segments.last().unwrap().ident == "ArkeCommand" => {
                         command_ident = Some(ident.ident);
                     }
+                    Type::Reference(reference) => {
+                        if let Type::Path(path) = *reference.elem {
+                            if path.path.segments.last().unwrap().ident == "Connection" {
+                                connection_ident = Some(ident.ident);
+                                connection_type = Some(path.into_token_stream());
+                            }
+                        }
+                    }
+                    _ => {}
                 }
             }
         }
     });
     let command_ident = command_ident.expect("Function must have an input variable `command`");
+    let connection_ident = connection_ident.unwrap_or_else(|| Ident::new("_connection", sig.ident.span()));
+    let connection_type = connection_type.unwrap_or_else(|| quote! { arke::server::connection::Connection });
     
     sig.inputs.into_iter().for_each(|input| {
         if let FnArg::Typed(PatType { pat, ty, .. }) = input {
@@ -152,12 +165,12 @@ pub fn command_handler(args: TokenStream, annotated_item: TokenStream) -> TokenS
 
         #[async_trait::async_trait]
         impl arke::server::command::CommandHandler for #ident {
-            async fn handle(&mut self, command: arke::server::command::ArkeCommand) -> arke::server::command::ArkeCommand {
-                #new_ident(std::sync::Arc::clone(&self.state), command).await
+            async fn handle(&mut self, connection: &mut arke::server::connection::Connection, command: arke::server::command::ArkeCommand) -> arke::server::command::ArkeCommand {
+                #new_ident(std::sync::Arc::clone(&self.state), connection, command).await
             }
         }
         
-        #vis async fn #new_ident(#state_ident: std::sync::Arc<tokio::sync::Mutex<#state_type>>, #command_ident: arke::server::command::ArkeCommand) -> arke::server::command::ArkeCommand {
+        #vis async fn #new_ident(#state_ident: std::sync::Arc<tokio::sync::Mutex<#state_type>>, #connection_ident: &mut #connection_type, #command_ident: arke::server::command::ArkeCommand) -> arke::server::command::ArkeCommand {
             match #command_ident {
                 #pattern => {
                     let mut #state_ident = #state_ident.lock().await;
@@ -0,0 +1,37 @@
+-- Accounts whose names collide under the column's collation can't coexist with
+-- the unique index. Keep the oldest one and rename the others to
+-- `user-<user_id>`, adding a `-<n>` suffix until the name isn't taken. These
+-- names are short enough and only use characters `normalize_username` accepts.
+CREATE PROCEDURE rename_colliding_usernames()
+BEGIN
+  DECLARE renamed_id INT;
+  DECLARE candidate VARCHAR(255);
+  DECLARE attempt INT;
+
+  rename_loop: LOOP
+    SET renamed_id = (
+      SELECT renamed.user_id
+        FROM user AS renamed
+        JOIN user AS kept ON kept.username = renamed.username AND kept.user_id < renamed.user_id
+        ORDER BY renamed.user_id
+        LIMIT 1
+    );
+    IF renamed_id IS NULL THEN
+      LEAVE rename_loop;
+    END IF;
+
+    SET attempt = 0;
+    SET candidate = CONCAT('user-', renamed_id);
+    WHILE EXISTS (SELECT 1 FROM user WHERE username = candidate) DO
+      SET attempt = attempt + 1;
+      SET candidate = CONCAT('user-', renamed_id, '-', attempt);
+    END WHILE;
+
+    UPDATE user SET username = candidate WHERE user_id = renamed_id;
+  END LOOP;
+END;
+
+CALL rename_colliding_usernames();
+DROP PROCEDURE rename_colliding_usernames;
+
+ALTER TABLE user ADD UNIQUE INDEX user_username_unique (username);
@@ -0,0 +1,9 @@
+CREATE TABLE message (
+  message_id bigint NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  recipient varchar(255) NOT NULL,
+  sender varchar(255),
+  kind tinyint unsigned NOT NULL,
+  content BLOB NOT NULL,
+  created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  INDEX message_recipient (recipient)
+);
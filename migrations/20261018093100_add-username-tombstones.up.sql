@@ -0,0 +1,4 @@
+CREATE TABLE username_tombstone (
+  username varchar(255) NOT NULL PRIMARY KEY,
+  expires_at timestamp NOT NULL
+);
@@ -0,0 +1,10 @@
+CREATE TABLE identity_key_history (
+  history_id bigint NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  username varchar(255) NOT NULL,
+  identity_key BLOB NOT NULL,
+  changed_at bigint unsigned NOT NULL,
+  INDEX identity_key_history_username (username)
+);
+
+INSERT INTO identity_key_history(username, identity_key, changed_at)
+  SELECT username, identity_key, UNIX_TIMESTAMP() FROM user;
@@ -0,0 +1,10 @@
+CREATE TABLE key_log (
+  leaf_index bigint unsigned NOT NULL PRIMARY KEY,
+  username varchar(255) NOT NULL,
+  identity_key BLOB NOT NULL,
+  logged_at bigint unsigned NOT NULL
+);
+
+INSERT INTO key_log(leaf_index, username, identity_key, logged_at)
+  SELECT ROW_NUMBER() OVER (ORDER BY history_id) - 1, username, identity_key, changed_at
+  FROM identity_key_history;
@@ -0,0 +1 @@
+ALTER TABLE user DROP COLUMN unidentified_access_key;
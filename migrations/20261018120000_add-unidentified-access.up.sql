@@ -0,0 +1 @@
+ALTER TABLE user ADD (unidentified_access_key VARBINARY(64));
@@ -0,0 +1,2 @@
+DROP TABLE group_member;
+DROP TABLE chat_group;
@@ -0,0 +1,13 @@
+CREATE TABLE chat_group (
+  group_id BINARY(16) NOT NULL PRIMARY KEY,
+  metadata BLOB NOT NULL,
+  created_at bigint unsigned NOT NULL
+);
+
+CREATE TABLE group_member (
+  group_id BINARY(16) NOT NULL,
+  username varchar(255) NOT NULL,
+  admin boolean NOT NULL DEFAULT FALSE,
+  PRIMARY KEY (group_id, username),
+  INDEX group_member_username (username)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE attachment (
+  attachment_id BINARY(16) NOT NULL PRIMARY KEY,
+  owner varchar(255) NOT NULL,
+  size bigint unsigned NOT NULL,
+  complete boolean NOT NULL DEFAULT FALSE,
+  created_at bigint unsigned NOT NULL,
+  expires_at bigint unsigned NOT NULL,
+  INDEX attachment_owner (owner),
+  INDEX attachment_expires_at (expires_at)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE profile (
+  username varchar(255) NOT NULL,
+  version varchar(64) NOT NULL,
+  ciphertext BLOB NOT NULL,
+  commitment VARBINARY(64) NOT NULL,
+  PRIMARY KEY (username, version)
+);
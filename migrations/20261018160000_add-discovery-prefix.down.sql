@@ -0,0 +1,2 @@
+ALTER TABLE user DROP INDEX user_discovery_prefix;
+ALTER TABLE user DROP COLUMN discovery_prefix;
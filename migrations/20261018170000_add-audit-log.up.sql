@@ -0,0 +1,11 @@
+CREATE TABLE audit_log (
+  entry_id bigint unsigned NOT NULL PRIMARY KEY,
+  recorded_at bigint unsigned NOT NULL,
+  event varchar(64) NOT NULL,
+  username varchar(255) NOT NULL,
+  peer_addr varchar(64),
+  details TEXT NOT NULL,
+  prev_hash BINARY(32) NOT NULL,
+  hash BINARY(32) NOT NULL,
+  INDEX audit_log_username (username)
+);
@@ -0,0 +1 @@
+ALTER TABLE user ADD (disabled boolean NOT NULL DEFAULT FALSE);
@@ -0,0 +1,6 @@
+ALTER TABLE user DROP INDEX user_discovery_prefix;
+ALTER TABLE user DROP COLUMN discovery_prefix;
+ALTER TABLE user ADD (
+  discovery_prefix BINARY(2) AS (LEFT(UNHEX(SHA2(username, 256)), 2)) STORED,
+  INDEX user_discovery_prefix (discovery_prefix)
+);
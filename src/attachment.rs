@@ -0,0 +1,277 @@
+//! Storage for encrypted attachments. Clients upload ciphertext in chunks and
+//! hand the resulting attachment id to recipients out of band, who can then
+//! download it until it expires. Blobs are kept as files in a storage directory
+//! while the `attachment` table tracks ownership, quotas and expiry.
+
+use openssl::sha::Sha256;
+use sqlx::{mysql::MySqlPool, FromRow};
+use std::{path::PathBuf, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{crypto, server::command::CommandError};
+
+pub const ATTACHMENT_ID_LENGTH: usize = 16;
+
+/// Largest amount of data that can be uploaded or downloaded with a single command.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Uploads that aren't finished within this time are discarded.
+pub const UPLOAD_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct AttachmentStore {
+    pub dir: PathBuf,
+    pub max_size: u64,
+    /// Total number of bytes a single user may have stored at once.
+    pub quota: u64,
+    pub expiry: Duration,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct AttachmentRecord {
+    owner: String,
+    size: u64,
+    complete: bool,
+}
+
+impl Default for AttachmentStore {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("attachments"),
+            max_size: 64 * 1024 * 1024,
+            quota: 256 * 1024 * 1024,
+            expiry: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl AttachmentStore {
+    fn path(&self, attachment_id: &[u8]) -> PathBuf {
+        self.dir.join(crypto::to_hex(attachment_id))
+    }
+
+    fn upload_path(&self, attachment_id: &[u8]) -> PathBuf {
+        self.path(attachment_id).with_extension("part")
+    }
+
+    async fn record(db: &MySqlPool, attachment_id: &[u8]) -> Result<Option<AttachmentRecord>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT owner, size, complete FROM attachment WHERE attachment_id = ? AND expires_at > ?",
+        )
+        .bind(attachment_id)
+        .bind(crate::unix_timestamp())
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Registers a new upload of `size` bytes and returns its id.
+    pub async fn begin_upload(&self, db: &MySqlPool, owner: &str, size: u64) -> Result<Vec<u8>, CommandError> {
+        if size == 0 || size > self.max_size {
+            return Err(CommandError::InvalidRequest {
+                msg: format!("Attachments must be between 1 and {} bytes", self.max_size),
+            });
+        }
+
+        let now = crate::unix_timestamp();
+        let (used,): (u64,) = sqlx::query_as(
+            "SELECT CAST(COALESCE(SUM(size), 0) AS UNSIGNED) FROM attachment \
+             WHERE owner = ? AND expires_at > ?",
+        )
+        .bind(owner)
+        .bind(now)
+        .fetch_one(db)
+        .await
+        .map_err(server_error)?;
+
+        if used.saturating_add(size) > self.quota {
+            return Err(CommandError::QuotaExceeded);
+        }
+
+        let attachment_id = crypto::random_bytes(ATTACHMENT_ID_LENGTH);
+        tokio::fs::create_dir_all(&self.dir).await.map_err(server_error)?;
+        tokio::fs::File::create(self.upload_path(&attachment_id))
+            .await
+            .map_err(server_error)?;
+
+        sqlx::query(
+            "INSERT INTO attachment(attachment_id, owner, size, created_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&attachment_id)
+        .bind(owner)
+        .bind(size)
+        .bind(now)
+        .bind(now + UPLOAD_TIMEOUT.as_secs())
+        .execute(db)
+        .await
+        .map_err(server_error)?;
+
+        Ok(attachment_id)
+    }
+
+    /// Appends `data` to an unfinished upload. Chunks must be sent in order, so
+    /// `offset` has to match the number of bytes received so far.
+    pub async fn upload_chunk(
+        &self,
+        db: &MySqlPool,
+        owner: &str,
+        attachment_id: &[u8],
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), CommandError> {
+        let record = self.pending_upload(db, owner, attachment_id).await?;
+        if data.len() > MAX_CHUNK_SIZE || offset.saturating_add(data.len() as u64) > record.size {
+            return Err(CommandError::InvalidRequest {
+                msg: "Chunk exceeds the attachment size".to_string(),
+            });
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(self.upload_path(attachment_id))
+            .await
+            .map_err(server_error)?;
+
+        let received = file.metadata().await.map_err(server_error)?.len();
+        if received != offset {
+            return Err(CommandError::InvalidRequest {
+                msg: format!("Expected chunk at offset {received}"),
+            });
+        }
+
+        file.write_all(data).await.map_err(server_error)?;
+        file.flush().await.map_err(server_error)
+    }
+
+    /// Completes an upload after checking that it is complete and that its
+    /// SHA-256 digest matches `digest`.
+    pub async fn finish_upload(
+        &self,
+        db: &MySqlPool,
+        owner: &str,
+        attachment_id: &[u8],
+        digest: &[u8],
+    ) -> Result<(), CommandError> {
+        let record = self.pending_upload(db, owner, attachment_id).await?;
+        let upload_path = self.upload_path(attachment_id);
+
+        let mut file = tokio::fs::File::open(&upload_path).await.map_err(server_error)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; MAX_CHUNK_SIZE];
+        let mut received = 0;
+        loop {
+            let n = file.read(&mut buffer).await.map_err(server_error)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            received += n as u64;
+        }
+
+        if received != record.size {
+            return Err(CommandError::InvalidRequest {
+                msg: format!("Received {received} of {} bytes", record.size),
+            });
+        }
+
+        if hasher.finish().as_slice() != digest {
+            return Err(CommandError::InvalidRequest {
+                msg: "Attachment digest mismatch".to_string(),
+            });
+        }
+
+        tokio::fs::rename(&upload_path, self.path(attachment_id))
+            .await
+            .map_err(server_error)?;
+
+        sqlx::query("UPDATE attachment SET complete = TRUE, expires_at = ? WHERE attachment_id = ?")
+            .bind(crate::unix_timestamp() + self.expiry.as_secs())
+            .bind(attachment_id)
+            .execute(db)
+            .await
+            .map_err(server_error)?;
+
+        Ok(())
+    }
+
+    async fn pending_upload(
+        &self,
+        db: &MySqlPool,
+        owner: &str,
+        attachment_id: &[u8],
+    ) -> Result<AttachmentRecord, CommandError> {
+        match Self::record(db, attachment_id).await.map_err(server_error)? {
+            Some(record) if record.owner == owner && !record.complete => Ok(record),
+            _ => Err(CommandError::AttachmentNotFound),
+        }
+    }
+
+    /// Reads up to `length` bytes of a finished attachment starting at `offset`.
+    /// Returns the data together with the total size of the attachment.
+    pub async fn download_chunk(
+        &self,
+        db: &MySqlPool,
+        attachment_id: &[u8],
+        offset: u64,
+        length: usize,
+    ) -> Result<(Vec<u8>, u64), CommandError> {
+        let record = match Self::record(db, attachment_id).await.map_err(server_error)? {
+            Some(record) if record.complete => record,
+            _ => return Err(CommandError::AttachmentNotFound),
+        };
+
+        let mut file = tokio::fs::File::open(self.path(attachment_id))
+            .await
+            .map_err(server_error)?;
+        file.seek(std::io::SeekFrom::Start(offset.min(record.size)))
+            .await
+            .map_err(server_error)?;
+
+        let mut data = Vec::with_capacity(length.min(MAX_CHUNK_SIZE));
+        file.take(length.min(MAX_CHUNK_SIZE) as u64)
+            .read_to_end(&mut data)
+            .await
+            .map_err(server_error)?;
+
+        Ok((data, record.size))
+    }
+
+    /// Deletes expired attachments and abandoned uploads. Returns the number of
+    /// attachments removed.
+    pub async fn purge_expired(&self, db: &MySqlPool) -> Result<u64, sqlx::Error> {
+        let now = crate::unix_timestamp();
+        let expired: Vec<(Vec<u8>,)> =
+            sqlx::query_as("SELECT attachment_id FROM attachment WHERE expires_at <= ?")
+                .bind(now)
+                .fetch_all(db)
+                .await?;
+
+        for (attachment_id,) in &expired {
+            self.remove_files(attachment_id).await;
+
+            sqlx::query("DELETE FROM attachment WHERE attachment_id = ?")
+                .bind(attachment_id)
+                .execute(db)
+                .await?;
+        }
+
+        Ok(expired.len() as u64)
+    }
+
+    /// Removes the stored file and any unfinished upload of an attachment.
+    pub async fn remove_files(&self, attachment_id: &[u8]) {
+        for path in [self.path(attachment_id), self.upload_path(attachment_id)] {
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Couldn't remove attachment {}: {err:?}", path.display());
+                }
+            }
+        }
+    }
+}
+
+fn server_error(err: impl std::fmt::Debug) -> CommandError {
+    tracing::error!("Attachment storage error: {err:?}");
+    CommandError::ServerError {
+        msg: "Attachment storage error".to_string(),
+    }
+}
@@ -0,0 +1,460 @@
+//! Tamper-evident log of security relevant events such as account creation,
+//! identity key changes, failed signature checks and deletions. Every entry
+//! includes the hash of the one before it, so altering or removing an entry
+//! breaks the chain from that point on, see [`verify`]. Entries are appended
+//! inside the transaction that makes the audited change, with the previous
+//! entry locked, so every writer continues the same chain.
+
+use openssl::sha::Sha256;
+use sqlx::{
+    mysql::{MySqlConnection, MySqlPool},
+    FromRow,
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::{crypto, transparency::Hash};
+
+/// `prev_hash` of the first entry.
+pub const GENESIS_HASH: Hash = [0; 32];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditEvent {
+    AccountCreated,
+    /// A new identity key was stored for the account, either its initial key
+    /// or a rotation.
+    IdentityKeyChanged {
+        identity_key: Vec<u8>,
+    },
+    /// The account's signed prekey and one-time prekeys were stored.
+    PrekeysUploaded {
+        signed_prekey: Vec<u8>,
+        one_time_prekeys: usize,
+    },
+    /// A signature supplied by a client didn't verify.
+    InvalidSignature {
+        msg: String,
+    },
+    AuthenticationFailed {
+        msg: String,
+    },
+    AccountDeleted,
+    /// An operator disabled or re-enabled the account.
+    AccountDisabled,
+    AccountEnabled,
+}
+
+impl AuditEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::AccountCreated => "account_created",
+            Self::IdentityKeyChanged { .. } => "identity_key_changed",
+            Self::PrekeysUploaded { .. } => "prekeys_uploaded",
+            Self::InvalidSignature { .. } => "invalid_signature",
+            Self::AuthenticationFailed { .. } => "authentication_failed",
+            Self::AccountDeleted => "account_deleted",
+            Self::AccountDisabled => "account_disabled",
+            Self::AccountEnabled => "account_enabled",
+        }
+    }
+
+    fn details(&self) -> String {
+        match self {
+            Self::AccountCreated
+            | Self::AccountDeleted
+            | Self::AccountDisabled
+            | Self::AccountEnabled => String::new(),
+            Self::IdentityKeyChanged { identity_key } => format!("fingerprint={}", fingerprint(identity_key)),
+            Self::PrekeysUploaded {
+                signed_prekey,
+                one_time_prekeys,
+            } => format!(
+                "signed_prekey_fingerprint={} one_time_prekeys={one_time_prekeys}",
+                fingerprint(signed_prekey)
+            ),
+            Self::InvalidSignature { msg } | Self::AuthenticationFailed { msg } => msg.clone(),
+        }
+    }
+}
+
+/// Hex encoded SHA-256 hash of a key, so entries identify keys without
+/// storing them.
+fn fingerprint(key: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    crypto::to_hex(&hasher.finish())
+}
+
+/// Failed authentications for accounts that don't exist would otherwise add
+/// an entry each, letting any client grow the log at will. Only the first of
+/// them from a client, see [`crate::peer_key`], is recorded per `window`,
+/// noting how many were left out before it.
+#[derive(Debug)]
+pub struct UnknownUserFailures {
+    pub window: Duration,
+    /// Start of the current window and failures left out in it, by client.
+    clients: HashMap<IpAddr, (Instant, u64)>,
+    last_sweep: Instant,
+}
+
+impl Default for UnknownUserFailures {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            clients: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+}
+
+impl UnknownUserFailures {
+    /// Counts a failure from `peer`. Returns the number of failures from the
+    /// same client left out since the last recorded one if this one should be
+    /// recorded.
+    pub fn admit(&mut self, peer: IpAddr) -> Option<u64> {
+        self.admit_at(peer, Instant::now())
+    }
+
+    fn admit_at(&mut self, peer: IpAddr, now: Instant) -> Option<u64> {
+        let window = self.window;
+        let admitted = match self.clients.entry(crate::peer_key(peer)) {
+            Entry::Occupied(mut client) if now.duration_since(client.get().0) < window => {
+                client.get_mut().1 += 1;
+                None
+            }
+            Entry::Occupied(mut client) => {
+                let (_, left_out) = std::mem::replace(client.get_mut(), (now, 0));
+                Some(left_out)
+            }
+            Entry::Vacant(client) => {
+                client.insert((now, 0));
+                Some(0)
+            }
+        };
+
+        if now.duration_since(self.last_sweep) >= window {
+            self.clients.retain(|client, (start, left_out)| {
+                let current = now.duration_since(*start) < window;
+                if !current && *left_out > 0 {
+                    tracing::warn!("{left_out} failed authentications for unknown users from {client} weren't audited");
+                }
+                current
+            });
+            self.last_sweep = now;
+        }
+
+        admitted
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct AuditEntry {
+    pub entry_id: u64,
+    pub recorded_at: u64,
+    pub event: String,
+    pub username: String,
+    pub peer_addr: Option<String>,
+    pub details: String,
+    pub prev_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+impl AuditEntry {
+    /// Creates the entry following the one with `entry_id - 1` and hash `prev_hash`.
+    fn new(
+        entry_id: u64,
+        prev_hash: &[u8],
+        event: &AuditEvent,
+        username: &str,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
+        let mut entry = Self {
+            entry_id,
+            recorded_at: crate::unix_timestamp(),
+            event: event.name().to_string(),
+            username: username.to_string(),
+            peer_addr: peer_addr.map(|addr| addr.to_string()),
+            details: event.details(),
+            prev_hash: prev_hash.to_vec(),
+            hash: vec![],
+        };
+        entry.hash = entry.compute_hash().to_vec();
+        entry
+    }
+
+    /// The hash of this entry as it should have been stored.
+    pub fn compute_hash(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.prev_hash);
+        hasher.update(&self.entry_id.to_be_bytes());
+        hasher.update(&self.recorded_at.to_be_bytes());
+        for field in [&self.event, &self.username] {
+            update_field(&mut hasher, field.as_bytes());
+        }
+        match &self.peer_addr {
+            Some(peer_addr) => {
+                hasher.update(&[1]);
+                update_field(&mut hasher, peer_addr.as_bytes());
+            }
+            None => hasher.update(&[0]),
+        }
+        update_field(&mut hasher, self.details.as_bytes());
+        hasher.finish()
+    }
+}
+
+/// Hashes `data` prefixed with its big endian length, so that adjacent fields
+/// can't be shifted into each other.
+fn update_field(hasher: &mut Sha256, data: &[u8]) {
+    hasher.update(&(data.len() as u64).to_be_bytes());
+    hasher.update(data);
+}
+
+/// Appends an event concerning `username` to the log as part of the
+/// transaction running on `conn` and returns the new entry. The latest entry
+/// stays locked until that transaction ends, so appends made by other
+/// transactions, processes or the admin commands wait and then continue the
+/// chain from this entry.
+pub async fn append(
+    conn: &mut MySqlConnection,
+    event: &AuditEvent,
+    username: &str,
+    peer_addr: Option<SocketAddr>,
+) -> Result<AuditEntry, sqlx::Error> {
+    let head: Option<(u64, Vec<u8>)> = sqlx::query_as(
+        "SELECT entry_id, hash FROM audit_log ORDER BY entry_id DESC LIMIT 1 FOR UPDATE",
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let entry = match head {
+        Some((entry_id, hash)) => AuditEntry::new(entry_id + 1, &hash, event, username, peer_addr),
+        None => AuditEntry::new(0, &GENESIS_HASH, event, username, peer_addr),
+    };
+
+    sqlx::query(
+        "INSERT INTO audit_log(entry_id, recorded_at, event, username, peer_addr, details, \
+         prev_hash, hash) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(entry.entry_id)
+    .bind(entry.recorded_at)
+    .bind(&entry.event)
+    .bind(&entry.username)
+    .bind(&entry.peer_addr)
+    .bind(&entry.details)
+    .bind(&entry.prev_hash)
+    .bind(&entry.hash)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(entry)
+}
+
+/// Appends an event that isn't tied to any other change in a transaction of its own.
+pub async fn record(
+    db: &MySqlPool,
+    event: &AuditEvent,
+    username: &str,
+    peer_addr: Option<SocketAddr>,
+) -> Result<AuditEntry, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let entry = append(&mut tx, event, username, peer_addr).await?;
+    tx.commit().await?;
+    Ok(entry)
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    Database(sqlx::Error),
+    /// The chain doesn't hold at `entry_id`.
+    Broken {
+        entry_id: u64,
+        msg: String,
+    },
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Database(err) => write!(f, "Couldn't read the audit log: {err}"),
+            Self::Broken { entry_id, msg } => {
+                write!(f, "Audit log is broken at entry {entry_id}: {msg}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+impl From<sqlx::Error> for AuditError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Checks every entry of the log against its hash and its predecessor.
+/// Returns the number of entries and the hash of the last one. Entries removed
+/// from the end can only be noticed by comparing that hash with one recorded
+/// earlier.
+pub async fn verify(db: &MySqlPool) -> Result<(u64, Hash), AuditError> {
+    let entries: Vec<AuditEntry> = sqlx::query_as(
+        "SELECT entry_id, recorded_at, event, username, peer_addr, details, prev_hash, hash \
+         FROM audit_log ORDER BY entry_id",
+    )
+    .fetch_all(db)
+    .await?;
+
+    verify_entries(&entries)
+}
+
+/// Checks a complete log, ordered by `entry_id`, see [`verify`].
+pub fn verify_entries(entries: &[AuditEntry]) -> Result<(u64, Hash), AuditError> {
+    let mut head = GENESIS_HASH;
+    for (expected_id, entry) in (0..).zip(entries) {
+        let broken = |msg: &str| AuditError::Broken {
+            entry_id: entry.entry_id,
+            msg: msg.to_string(),
+        };
+
+        if entry.entry_id != expected_id {
+            return Err(AuditError::Broken {
+                entry_id: expected_id,
+                msg: "entry is missing".to_string(),
+            });
+        }
+        if entry.prev_hash != head {
+            return Err(broken("previous hash doesn't match the preceding entry"));
+        }
+        let hash = entry.compute_hash();
+        if entry.hash != hash {
+            return Err(broken("hash doesn't match the entry's contents"));
+        }
+        head = hash;
+    }
+
+    Ok((entries.len() as u64, head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(events: &[AuditEvent]) -> Vec<AuditEntry> {
+        let peer_addr = Some(SocketAddr::from(([192, 0, 2, 1], 50000)));
+        let mut entries: Vec<AuditEntry> = vec![];
+        for (entry_id, event) in (0..).zip(events) {
+            let prev_hash = entries.last().map_or(GENESIS_HASH.to_vec(), |e| e.hash.clone());
+            entries.push(AuditEntry::new(entry_id, &prev_hash, event, "alice", peer_addr));
+        }
+        entries
+    }
+
+    fn events() -> Vec<AuditEvent> {
+        vec![
+            AuditEvent::AccountCreated,
+            AuditEvent::IdentityKeyChanged {
+                identity_key: vec![1, 2, 3],
+            },
+            AuditEvent::AuthenticationFailed {
+                msg: "Challenge signature is invalid".to_string(),
+            },
+            AuditEvent::AccountDeleted,
+        ]
+    }
+
+    fn broken_at(result: Result<(u64, Hash), AuditError>) -> u64 {
+        match result {
+            Err(AuditError::Broken { entry_id, .. }) => entry_id,
+            other => panic!("Expected a broken chain, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let entries = chain(&events());
+        let (count, head) = verify_entries(&entries).unwrap();
+        assert_eq!(count, 4);
+        assert_eq!(head.to_vec(), entries[3].hash);
+
+        assert_eq!(verify_entries(&[]).unwrap(), (0, GENESIS_HASH));
+    }
+
+    #[test]
+    fn altered_entry_is_detected() {
+        let mut entries = chain(&events());
+        entries[1].username = "mallory".to_string();
+        assert_eq!(broken_at(verify_entries(&entries)), 1);
+
+        let mut entries = chain(&events());
+        entries[2].peer_addr = None;
+        assert_eq!(broken_at(verify_entries(&entries)), 2);
+    }
+
+    #[test]
+    fn rehashed_entry_breaks_its_successor() {
+        let mut entries = chain(&events());
+        entries[1].details = "fingerprint=00".to_string();
+        entries[1].hash = entries[1].compute_hash().to_vec();
+        assert_eq!(broken_at(verify_entries(&entries)), 2);
+    }
+
+    #[test]
+    fn removed_entry_is_detected() {
+        let mut entries = chain(&events());
+        entries.remove(1);
+        assert_eq!(broken_at(verify_entries(&entries)), 1);
+
+        let mut entries = chain(&events());
+        entries.remove(0);
+        assert_eq!(broken_at(verify_entries(&entries)), 0);
+    }
+
+    #[test]
+    fn prekey_uploads_record_fingerprint_and_count() {
+        let event = AuditEvent::PrekeysUploaded {
+            signed_prekey: vec![1, 2, 3],
+            one_time_prekeys: 100,
+        };
+        assert_eq!(
+            event.details(),
+            format!("signed_prekey_fingerprint={} one_time_prekeys=100", fingerprint(&[1, 2, 3]))
+        );
+    }
+
+    #[test]
+    fn unknown_user_failures_are_recorded_once_per_window() {
+        let start = Instant::now();
+        let mut failures = UnknownUserFailures {
+            last_sweep: start,
+            ..Default::default()
+        };
+        let window = failures.window;
+        let peer = |last: u16| IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, last]);
+
+        assert_eq!(failures.admit_at(peer(1), start), Some(0));
+        assert_eq!(failures.admit_at(peer(2), start), None);
+        assert_eq!(failures.admit_at(peer(1), start + window / 2), None);
+        assert_eq!(failures.admit_at(IpAddr::from([192, 0, 2, 1]), start), Some(0));
+
+        assert_eq!(failures.admit_at(peer(1), start + window - Duration::from_secs(1)), None);
+        assert_eq!(failures.admit_at(peer(1), start + window), Some(3));
+        assert_eq!(failures.admit_at(peer(1), start + window), None);
+
+        // The sweep drops clients whose window is over.
+        assert_eq!(failures.clients.len(), 1);
+    }
+
+    #[test]
+    fn fields_cant_be_shifted_into_each_other() {
+        let peer_addr = None;
+        let a = AuditEntry::new(0, &GENESIS_HASH, &AuditEvent::AccountCreated, "ab", peer_addr);
+        let mut b = a.clone();
+        b.event = format!("{}a", a.event);
+        b.username = "b".to_string();
+        assert_ne!(a.compute_hash(), b.compute_hash());
+    }
+}
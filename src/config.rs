@@ -0,0 +1,691 @@
+//! Server configuration. Values are layered from built-in defaults, an optional
+//! TOML file, environment variables and command line flags, in increasing
+//! order of precedence.
+
+use crate::{
+    attachment::AttachmentStore,
+    logging::LogFormat,
+    server::{
+        ratelimit::RateLimit,
+        state::DEFAULT_USERNAME_COOLDOWN,
+        tls::{
+            self, ClientAuth, Passphrase, ReloadableCert, TlsError, TlsSettings,
+            DEFAULT_RELOAD_INTERVAL,
+        },
+        Timeouts, DEFAULT_SHUTDOWN_TIMEOUT,
+    },
+};
+use serde::Deserialize;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::level_filters::LevelFilter;
+
+/// Configuration file read when none is given explicitly, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "arke.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, err: std::io::Error },
+    Parse { path: PathBuf, err: toml::de::Error },
+    Invalid { msg: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, err } => write!(f, "Couldn't read {}: {err}", path.display()),
+            Self::Parse { path, err } => write!(f, "Invalid config file {}: {err}", path.display()),
+            Self::Invalid { msg } => write!(f, "Invalid configuration: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name this server is reachable under.
+    pub hostname: String,
+    #[serde(deserialize_with = "from_str")]
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    /// Key used to sign tree heads, sender certificates and key timestamps.
+    pub signing_key: PathBuf,
+    /// Addresses to accept connections on, written as `[[listen]]` tables.
+    pub listen: Vec<ListenConfig>,
+    /// TLS settings used by listeners that don't have their own.
+    pub tls: TlsConfig,
+    /// How often certificate files are checked for changes, zero disables the
+    /// check. Certificates are also reloaded on SIGHUP.
+    #[serde(deserialize_with = "duration")]
+    pub tls_reload_interval: Duration,
+    pub database: DatabaseConfig,
+    pub limits: LimitsConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub registration: RegistrationConfig,
+    pub attachments: AttachmentConfig,
+    pub metrics: MetricsConfig,
+    pub health: HealthConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hostname: "localhost".to_string(),
+            log_level: if cfg!(debug_assertions) {
+                LevelFilter::DEBUG
+            } else {
+                LevelFilter::INFO
+            },
+            log_format: LogFormat::default(),
+            signing_key: PathBuf::from("signingKey.pem"),
+            listen: vec![ListenConfig::default()],
+            tls: TlsConfig::default(),
+            tls_reload_interval: DEFAULT_RELOAD_INTERVAL,
+            database: DatabaseConfig::default(),
+            limits: LimitsConfig::default(),
+            rate_limits: RateLimitsConfig::default(),
+            registration: RegistrationConfig::default(),
+            attachments: AttachmentConfig::default(),
+            metrics: MetricsConfig::default(),
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Also accept IPv4 connections when listening on an IPv6 address.
+    pub dual_stack: bool,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            port: 8080,
+            dual_stack: false,
+            tls: None,
+        }
+    }
+}
+
+impl ListenConfig {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificate used when no `sni` entry matches the requested server name.
+    pub cert: PathBuf,
+    pub private_key: PathBuf,
+    /// Environment variable holding the passphrase of an encrypted private key.
+    pub passphrase_env: Option<String>,
+    /// File holding the passphrase of an encrypted private key.
+    pub passphrase_file: Option<PathBuf>,
+    /// Certificates selected by the server name clients request, written as
+    /// `[[tls.sni]]` tables.
+    pub sni: Vec<SniConfig>,
+    /// CA bundle client certificates are verified against. Client
+    /// authentication (mTLS) is disabled if unset.
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: ClientAuthMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// Clients without a certificate can still connect.
+    Optional,
+    #[default]
+    Required,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::from("cert.pem"),
+            private_key: PathBuf::from("privateKey.pem"),
+            passphrase_env: None,
+            passphrase_file: None,
+            sni: vec![],
+            client_ca: None,
+            client_auth: ClientAuthMode::default(),
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Loads the default and SNI certificates.
+    pub fn load(&self) -> Result<TlsSettings, TlsError> {
+        let passphrase = key_passphrase(&self.passphrase_env, &self.passphrase_file);
+        let mut settings = TlsSettings::from_files(&self.cert, &self.private_key, passphrase)?;
+        for sni in &self.sni {
+            let passphrase = key_passphrase(&sni.passphrase_env, &sni.passphrase_file);
+            for server_name in &sni.server_names {
+                let cert =
+                    ReloadableCert::from_files(&sni.cert, &sni.private_key, passphrase.clone())?;
+                settings = settings.with_server_name(server_name, cert);
+            }
+        }
+
+        if let Some(client_ca) = &self.client_ca {
+            let roots = tls::load_client_ca(client_ca)?;
+            settings = settings.with_client_auth(match self.client_auth {
+                ClientAuthMode::Optional => ClientAuth::Optional(roots),
+                ClientAuthMode::Required => ClientAuth::Required(roots),
+            });
+        }
+        Ok(settings)
+    }
+}
+
+fn key_passphrase(env: &Option<String>, file: &Option<PathBuf>) -> Option<Passphrase> {
+    match (env, file) {
+        (Some(name), _) => Some(Passphrase::Env(name.clone())),
+        (None, Some(path)) => Some(Passphrase::File(path.clone())),
+        (None, None) => None,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniConfig {
+    /// Server names this certificate is used for, e.g. `chat.example.com` or
+    /// `*.example.com`.
+    pub server_names: Vec<String>,
+    pub cert: PathBuf,
+    pub private_key: PathBuf,
+    pub passphrase_env: Option<String>,
+    pub passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: Option<String>,
+    pub max_connections: u32,
+    #[serde(deserialize_with = "duration")]
+    pub acquire_timeout: Duration,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            max_connections: 10,
+            acquire_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub refuse_before_tls: bool,
+    pub outbound_queue_size: usize,
+    #[serde(deserialize_with = "duration")]
+    pub handshake_timeout: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub idle_timeout: Duration,
+    #[serde(deserialize_with = "option_duration")]
+    pub max_connection_lifetime: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        let timeouts = Timeouts::default();
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            refuse_before_tls: true,
+            outbound_queue_size: 32,
+            handshake_timeout: timeouts.handshake,
+            idle_timeout: timeouts.idle,
+            max_connection_lifetime: timeouts.max_lifetime,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+}
+
+/// Per command token buckets, applied to every client address and, once
+/// authenticated, to every account.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    pub create_user: RateLimitConfig,
+    pub registration_challenge: RateLimitConfig,
+    pub auth_challenge: RateLimitConfig,
+    pub authenticate: RateLimitConfig,
+    pub get_prekey_bundle: RateLimitConfig,
+    pub send_message: RateLimitConfig,
+    pub discover_contacts: RateLimitConfig,
+    pub begin_upload: RateLimitConfig,
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        let limit = |burst, secs| RateLimitConfig {
+            burst,
+            interval: Duration::from_secs(secs),
+        };
+        Self {
+            create_user: limit(5, 60 * 60),
+            registration_challenge: limit(20, 60),
+            auth_challenge: limit(20, 60),
+            authenticate: limit(20, 60),
+            get_prekey_bundle: limit(100, 60),
+            send_message: limit(200, 1),
+            discover_contacts: limit(10, 60),
+            begin_upload: limit(20, 60),
+        }
+    }
+}
+
+impl RateLimitsConfig {
+    fn iter(&self) -> impl Iterator<Item = (&'static str, &RateLimitConfig)> {
+        [
+            ("create_user", &self.create_user),
+            ("registration_challenge", &self.registration_challenge),
+            ("auth_challenge", &self.auth_challenge),
+            ("authenticate", &self.authenticate),
+            ("get_prekey_bundle", &self.get_prekey_bundle),
+            ("send_message", &self.send_message),
+            ("discover_contacts", &self.discover_contacts),
+            ("begin_upload", &self.begin_upload),
+        ]
+        .into_iter()
+    }
+}
+
+/// Up to `burst` commands may be sent at once, after which one more is
+/// allowed every `interval`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub burst: u32,
+    #[serde(deserialize_with = "duration")]
+    pub interval: Duration,
+}
+
+impl From<RateLimitConfig> for RateLimit {
+    fn from(config: RateLimitConfig) -> Self {
+        RateLimit::new(config.burst, config.interval)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationConfig {
+    /// Proof of work difficulty required for registration, disabled if unset.
+    pub pow_difficulty: Option<u8>,
+    #[serde(deserialize_with = "duration")]
+    pub username_cooldown: Duration,
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        Self {
+            pow_difficulty: None,
+            username_cooldown: DEFAULT_USERNAME_COOLDOWN,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttachmentConfig {
+    pub dir: PathBuf,
+    pub max_size: u64,
+    pub quota: u64,
+    #[serde(deserialize_with = "duration")]
+    pub expiry: Duration,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        let store = AttachmentStore::default();
+        Self {
+            dir: store.dir,
+            max_size: store.max_size,
+            quota: store.quota,
+            expiry: store.expiry,
+        }
+    }
+}
+
+impl From<AttachmentConfig> for AttachmentStore {
+    fn from(config: AttachmentConfig) -> Self {
+        Self {
+            dir: config.dir,
+            max_size: config.max_size,
+            quota: config.quota,
+            expiry: config.expiry,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address of the plain HTTP listener serving `/metrics`, disabled if unset.
+    pub address: Option<SocketAddr>,
+    /// How often database and prekey gauges are updated.
+    #[serde(deserialize_with = "duration")]
+    pub sample_interval: Duration,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            sample_interval: Duration::from_secs(15),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Address of the plain HTTP listener serving `/healthz` and `/readyz`,
+    /// disabled if unset. May be the same as the metrics address.
+    pub address: Option<SocketAddr>,
+}
+
+/// Settings that can be given on the command line or through the environment,
+/// overriding the configuration file.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ConfigOverrides {
+    /// Name this server is reachable under
+    #[arg(long, env = "ARKE_HOSTNAME")]
+    pub hostname: Option<String>,
+    /// Address to serve Prometheus metrics on over plain HTTP
+    #[arg(long, env = "METRICS_ADDRESS")]
+    pub metrics_address: Option<SocketAddr>,
+    /// Address to serve health checks on over plain HTTP
+    #[arg(long, env = "HEALTH_ADDRESS")]
+    pub health_address: Option<SocketAddr>,
+    /// Log level (off, error, warn, info, debug, trace)
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    /// Log output format
+    #[arg(long, env = "LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Addresses to listen on, replacing the listeners in the config file
+    #[arg(long, env = "ARKE_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+    /// Address of the first listener
+    #[arg(long, env = "BIND_ADDRESS")]
+    pub bind_address: Option<IpAddr>,
+    /// Port of the first listener
+    #[arg(long, env = "BIND_PORT")]
+    pub bind_port: Option<u16>,
+    /// PEM file containing the TLS certificate chain
+    #[arg(long, env = "TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file containing the TLS private key
+    #[arg(long, env = "TLS_PRIVATE_KEY")]
+    pub tls_private_key: Option<PathBuf>,
+    /// MySQL connection URL
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    pub database_url: Option<String>,
+    /// PEM file containing the server signing key, generated if missing
+    #[arg(long, env = "SIGNING_KEY")]
+    pub signing_key: Option<PathBuf>,
+    /// Maximum number of open connections
+    #[arg(long, env = "MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+    /// Maximum number of open connections from a single address or IPv6 /64 network
+    #[arg(long, env = "MAX_CONNECTIONS_PER_IP")]
+    pub max_connections_per_ip: Option<usize>,
+    /// Require a proof of work of this many bits for registration
+    #[arg(long, env = "REGISTRATION_POW_DIFFICULTY")]
+    pub registration_pow_difficulty: Option<u8>,
+    /// How long usernames of deleted accounts stay reserved
+    #[arg(long, env = "USERNAME_COOLDOWN", value_parser = humantime::parse_duration)]
+    pub username_cooldown: Option<Duration>,
+    /// Directory attachments are stored in
+    #[arg(long, env = "ATTACHMENT_DIR")]
+    pub attachment_dir: Option<PathBuf>,
+    /// Attachment storage quota per user in bytes
+    #[arg(long, env = "ATTACHMENT_QUOTA")]
+    pub attachment_quota: Option<u64>,
+    /// How long attachments are kept
+    #[arg(long, env = "ATTACHMENT_EXPIRY", value_parser = humantime::parse_duration)]
+    pub attachment_expiry: Option<Duration>,
+}
+
+impl Config {
+    /// Reads the configuration file at `path`, or [`DEFAULT_CONFIG_PATH`] if it
+    /// exists and no path is given, and applies `overrides` on top of it.
+    pub fn load(path: Option<&Path>, overrides: ConfigOverrides) -> Result<Self, ConfigError> {
+        let default_path = Path::new(DEFAULT_CONFIG_PATH);
+        let path = path.or_else(|| default_path.exists().then_some(default_path));
+
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply(overrides);
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|err| ConfigError::Io {
+            path: path.to_path_buf(),
+            err,
+        })?;
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse {
+            path: path.to_path_buf(),
+            err,
+        })
+    }
+
+    pub fn apply(&mut self, overrides: ConfigOverrides) {
+        fn set<T>(target: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+
+        set(&mut self.hostname, overrides.hostname);
+        set(&mut self.log_level, overrides.log_level);
+        set(&mut self.log_format, overrides.log_format);
+        if !overrides.listen.is_empty() {
+            self.listen = overrides
+                .listen
+                .into_iter()
+                .map(|addr| ListenConfig {
+                    address: addr.ip(),
+                    port: addr.port(),
+                    ..Default::default()
+                })
+                .collect();
+        }
+        if overrides.bind_address.is_some() || overrides.bind_port.is_some() {
+            if self.listen.is_empty() {
+                self.listen.push(ListenConfig::default());
+            }
+            set(&mut self.listen[0].address, overrides.bind_address);
+            set(&mut self.listen[0].port, overrides.bind_port);
+        }
+        set(&mut self.tls.cert, overrides.tls_cert);
+        set(&mut self.tls.private_key, overrides.tls_private_key);
+        set(&mut self.signing_key, overrides.signing_key);
+        set(&mut self.attachments.dir, overrides.attachment_dir);
+        set(&mut self.attachments.quota, overrides.attachment_quota);
+        set(&mut self.attachments.expiry, overrides.attachment_expiry);
+        set(
+            &mut self.registration.username_cooldown,
+            overrides.username_cooldown,
+        );
+
+        if overrides.metrics_address.is_some() {
+            self.metrics.address = overrides.metrics_address;
+        }
+        if overrides.health_address.is_some() {
+            self.health.address = overrides.health_address;
+        }
+        if overrides.database_url.is_some() {
+            self.database.url = overrides.database_url;
+        }
+        if overrides.max_connections.is_some() {
+            self.limits.max_connections = overrides.max_connections;
+        }
+        if overrides.max_connections_per_ip.is_some() {
+            self.limits.max_connections_per_ip = overrides.max_connections_per_ip;
+        }
+        if overrides.registration_pow_difficulty.is_some() {
+            self.registration.pow_difficulty = overrides.registration_pow_difficulty;
+        }
+    }
+
+    /// Checks for settings that parse but can't work, such as missing files.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid { msg });
+
+        if self.hostname.trim().is_empty() {
+            return invalid("hostname must not be empty".to_string());
+        }
+        if self.database.url.as_deref().unwrap_or_default().is_empty() {
+            return invalid("database.url (or DATABASE_URL) must be set".to_string());
+        }
+        if self.database.max_connections == 0 {
+            return invalid("database.max_connections must be at least 1".to_string());
+        }
+        if self.limits.outbound_queue_size == 0 {
+            return invalid("limits.outbound_queue_size must be at least 1".to_string());
+        }
+        if self.limits.max_connections == Some(0) || self.limits.max_connections_per_ip == Some(0) {
+            return invalid("connection limits must be at least 1".to_string());
+        }
+        for (command, limit) in self.rate_limits.iter() {
+            if limit.burst == 0 || limit.interval.is_zero() {
+                return invalid(format!(
+                    "rate_limits.{command} needs a burst of at least 1 and a non-zero interval"
+                ));
+            }
+        }
+        if self.metrics.sample_interval.is_zero() {
+            return invalid("metrics.sample_interval must not be zero".to_string());
+        }
+
+        if self.listen.is_empty() {
+            return invalid("at least one listen address is required".to_string());
+        }
+
+        for (index, listen) in self.listen.iter().enumerate() {
+            let addr = listen.socket_addr();
+            if self.listen[..index]
+                .iter()
+                .any(|other| other.socket_addr() == addr)
+            {
+                return invalid(format!("listen address {addr} is configured twice"));
+            }
+
+            let tls = self.listener_tls(listen);
+            let files = [&tls.cert, &tls.private_key]
+                .into_iter()
+                .chain(tls.sni.iter().flat_map(|sni| [&sni.cert, &sni.private_key]))
+                .chain(&tls.client_ca);
+            for path in files {
+                if !path.is_file() {
+                    return invalid(format!(
+                        "TLS file {} for {addr} is not a readable file",
+                        path.display()
+                    ));
+                }
+            }
+
+            let passphrases = std::iter::once((&tls.passphrase_env, &tls.passphrase_file)).chain(
+                tls.sni
+                    .iter()
+                    .map(|sni| (&sni.passphrase_env, &sni.passphrase_file)),
+            );
+            for (env, file) in passphrases {
+                if env.is_some() && file.is_some() {
+                    return invalid(format!(
+                        "TLS settings for {addr} set both passphrase_env and passphrase_file"
+                    ));
+                }
+            }
+
+            if tls.sni.iter().any(|sni| sni.server_names.is_empty()) {
+                return invalid(format!("TLS sni entry for {addr} has no server_names"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// TLS settings of a listener, falling back to the top level `[tls]` table.
+    pub fn listener_tls<'a>(&'a self, listen: &'a ListenConfig) -> &'a TlsConfig {
+        listen.tls.as_ref().unwrap_or(&self.tls)
+    }
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(serde::de::Error::custom)
+}
+
+/// Durations are written in the humantime format, e.g. `"30s"` or `"1h 30m"`.
+fn duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+fn option_duration<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| humantime::parse_duration(&value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_matches_defaults() {
+        let config: Config = toml::from_str(include_str!("../arke.example.toml")).unwrap();
+        let defaults = RateLimitsConfig::default();
+        for ((command, limit), (_, default)) in config.rate_limits.iter().zip(defaults.iter()) {
+            assert_eq!((limit.burst, limit.interval), (default.burst, default.interval), "{command}");
+        }
+    }
+
+    #[test]
+    fn rate_limits_can_be_overridden() {
+        let config: Config = toml::from_str(
+            "[rate_limits]\nsend_message = { burst = 10, interval = \"2s\" }",
+        )
+        .unwrap();
+        assert_eq!(config.rate_limits.send_message.burst, 10);
+        assert_eq!(config.rate_limits.send_message.interval, Duration::from_secs(2));
+        assert_eq!(config.rate_limits.create_user.burst, 5);
+    }
+}
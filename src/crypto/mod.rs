@@ -1,9 +1,12 @@
 use openssl::{
-    ec::EcKey,
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    nid::Nid,
     pkey::{PKey, Private, Public},
 };
 use serde::{Deserialize, Serialize};
 use sqlx::Type;
+use std::{io::Write, path::Path};
 
 #[derive(Type, Default, Debug, Clone, Serialize, Deserialize)]
 #[sqlx(transparent)]
@@ -13,6 +16,15 @@ impl PublicKey {
     pub fn ec_key(&self) -> Result<EcKey<Public>, openssl::error::ErrorStack> {
         EcKey::public_key_from_pem(&self.0)
     }
+
+    /// Checks a DER encoded ECDSA `signature` over `data` against this key.
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let (Ok(key), Ok(sig)) = (self.ec_key(), EcdsaSig::from_der(signature)) else {
+            return false;
+        };
+
+        matches!(sig.verify(data, &key), Ok(true))
+    }
 }
 
 impl AsRef<[u8]> for PublicKey {
@@ -21,6 +33,18 @@ impl AsRef<[u8]> for PublicKey {
     }
 }
 
+/// Returns `len` cryptographically secure random bytes.
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    openssl::rand::rand_bytes(&mut buf).expect("Couldn't generate random bytes");
+    buf
+}
+
+/// Encodes `bytes` as lowercase hexadecimal.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct PrivateKey {
     data: Vec<u8>,
@@ -28,22 +52,77 @@ pub struct PrivateKey {
 
 impl PrivateKey {
     pub async fn generate() -> Result<(PrivateKey, PublicKey), openssl::error::ErrorStack> {
-        let ec_key = tokio::task::spawn_blocking(|| PKey::generate_x25519())
+        let ec_key = tokio::task::spawn_blocking(PKey::generate_x25519)
             .await
             .expect("Couldn't join key generation thread")?
             .ec_key()?;
 
         let private = PrivateKey {
-            data: Vec::from(ec_key.private_key_to_pem()?),
+            data: ec_key.private_key_to_pem()?,
         };
-        let public = PublicKey(Vec::from(ec_key.public_key_to_pem()?));
+        let public = PublicKey(ec_key.public_key_to_pem()?);
 
         Ok((private, public))
     }
 }
 
-impl Into<EcKey<Private>> for PrivateKey {
-    fn into(self) -> EcKey<Private> {
-        EcKey::private_key_from_pem(&self.data).unwrap()
+impl From<PrivateKey> for EcKey<Private> {
+    fn from(value: PrivateKey) -> EcKey<Private> {
+        EcKey::private_key_from_pem(&value.data).unwrap()
+    }
+}
+
+/// The server's own ECDSA P-256 key used to sign statements handed out to
+/// clients. Clients are expected to pin the matching public key.
+#[derive(Debug, Clone)]
+pub struct SigningKey {
+    key: EcKey<Private>,
+}
+
+impl SigningKey {
+    pub fn generate() -> Result<Self, openssl::error::ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        Ok(Self {
+            key: EcKey::generate(&group)?,
+        })
+    }
+
+    /// Loads a PEM encoded key from `path`, generating and saving a new one if
+    /// the file doesn't exist yet.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let to_io = |err: openssl::error::ErrorStack| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+
+        match std::fs::read(path) {
+            Ok(pem) => Ok(Self {
+                key: EcKey::private_key_from_pem(&pem).map_err(to_io)?,
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let key = Self::generate().map_err(to_io)?;
+                let pem = key.key.private_key_to_pem().map_err(to_io)?;
+                // Created with restricted permissions so the key is never readable by others.
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(0o600);
+                }
+                options.open(path)?.write_all(&pem)?;
+                Ok(key)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Signs the SHA-256 digest of `data`, returning a DER encoded ECDSA signature.
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        EcdsaSig::sign(&openssl::sha::sha256(data), &self.key)
+            .and_then(|sig| sig.to_der())
+            .expect("Couldn't sign data")
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.key.public_key_to_pem().expect("Couldn't encode public key"))
     }
 }
@@ -0,0 +1,163 @@
+//! Contact discovery by truncated username hashes. Clients send the first
+//! [`PREFIX_LENGTH`] bytes of the SHA-256 hash of each normalized username in
+//! their address book and receive every registered username sharing one of
+//! those prefixes, which they then match against the full hashes locally. The
+//! prefixes are short enough that each is shared by many usernames, so the
+//! server can't tell which of them a client was looking for. To keep the
+//! `user` table from being enumerated, every account may only look up a
+//! limited number of prefixes per window.
+
+use sqlx::{mysql::MySqlPool, MySql, QueryBuilder};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::server::command::CommandError;
+
+pub const PREFIX_LENGTH: usize = 2;
+pub const MAX_PREFIXES_PER_REQUEST: usize = 1024;
+
+#[derive(Debug)]
+pub struct DiscoveryLimiter {
+    pub window: Duration,
+    pub max_prefixes: u32,
+    usage: HashMap<String, (Instant, u32)>,
+    last_sweep: Instant,
+}
+
+impl Default for DiscoveryLimiter {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(24 * 60 * 60),
+            max_prefixes: 10_000,
+            usage: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+}
+
+impl DiscoveryLimiter {
+    /// Charges `count` lookups to `username`, failing if that would exceed
+    /// their allowance for the current window.
+    pub fn charge(&mut self, username: &str, count: u32) -> Result<(), CommandError> {
+        self.charge_at(username, count, Instant::now())
+    }
+
+    fn charge_at(&mut self, username: &str, count: u32, now: Instant) -> Result<(), CommandError> {
+        // Expired windows are reset when their account looks up prefixes again
+        // and only swept once per window, instead of on every call.
+        let window = self.window;
+        if now.duration_since(self.last_sweep) >= window {
+            self.usage.retain(|_, (start, _)| now.duration_since(*start) < window);
+            self.last_sweep = now;
+        }
+
+        let (start, used) = self
+            .usage
+            .entry(username.to_string())
+            .or_insert((now, 0));
+        if now.duration_since(*start) >= window {
+            *start = now;
+            *used = 0;
+        }
+
+        if used.saturating_add(count) > self.max_prefixes {
+            return Err(CommandError::QuotaExceeded);
+        }
+
+        *used += count;
+        Ok(())
+    }
+}
+
+/// Checks the number and length of `prefixes` sent in a single request.
+pub fn validate_prefixes(prefixes: &[Vec<u8>]) -> Result<(), CommandError> {
+    if prefixes.len() > MAX_PREFIXES_PER_REQUEST {
+        return Err(CommandError::InvalidRequest {
+            msg: format!("At most {MAX_PREFIXES_PER_REQUEST} prefixes may be sent at once"),
+        });
+    }
+
+    if prefixes.iter().any(|p| p.len() != PREFIX_LENGTH) {
+        return Err(CommandError::InvalidRequest {
+            msg: format!("Prefixes must be {PREFIX_LENGTH} bytes long"),
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns the registered usernames whose hash starts with one of `prefixes`.
+pub async fn discover(db: &MySqlPool, prefixes: &[Vec<u8>]) -> Result<Vec<String>, CommandError> {
+    validate_prefixes(prefixes)?;
+    if prefixes.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut query = QueryBuilder::<MySql>::new("SELECT username FROM user WHERE discovery_prefix IN (");
+    let mut separated = query.separated(", ");
+    for prefix in prefixes {
+        separated.push_bind(prefix);
+    }
+    separated.push_unseparated(")");
+
+    let usernames: Vec<(String,)> = query.build_query_as().fetch_all(db).await.map_err(|err| {
+        tracing::error!("Couldn't discover contacts: {err:?}");
+        CommandError::ServerError {
+            msg: "Couldn't discover contacts!".to_string(),
+        }
+    })?;
+
+    Ok(usernames.into_iter().map(|(username,)| username).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_are_validated() {
+        assert!(validate_prefixes(&[]).is_ok());
+        assert!(validate_prefixes(&vec![vec![0; PREFIX_LENGTH]; MAX_PREFIXES_PER_REQUEST]).is_ok());
+        assert!(validate_prefixes(&vec![vec![0; PREFIX_LENGTH]; MAX_PREFIXES_PER_REQUEST + 1]).is_err());
+        assert!(validate_prefixes(&[vec![0; PREFIX_LENGTH], vec![0; PREFIX_LENGTH + 1]]).is_err());
+    }
+
+    #[test]
+    fn limiter_refuses_lookups_over_the_allowance() {
+        let mut limiter = DiscoveryLimiter {
+            max_prefixes: 10,
+            ..Default::default()
+        };
+        assert!(limiter.charge("alice", 6).is_ok());
+        assert!(limiter.charge("alice", 5).is_err());
+        assert!(limiter.charge("alice", 4).is_ok());
+        assert!(limiter.charge("bob", 10).is_ok());
+    }
+
+    #[test]
+    fn limiter_resets_and_sweeps_expired_windows() {
+        let start = Instant::now();
+        let mut limiter = DiscoveryLimiter {
+            max_prefixes: 10,
+            last_sweep: start,
+            ..Default::default()
+        };
+        let window = limiter.window;
+        assert!(limiter.charge_at("alice", 10, start).is_ok());
+        assert!(limiter.charge_at("bob", 10, start + window / 2).is_ok());
+        assert!(limiter.charge_at("alice", 1, start + window / 2).is_err());
+
+        // Alice's window is over, Bob's isn't and nothing is swept yet.
+        assert!(limiter.charge_at("alice", 10, start + window - Duration::from_secs(1)).is_err());
+        assert!(limiter.charge_at("alice", 10, start + window).is_ok());
+        assert_eq!(limiter.usage.len(), 2);
+
+        // A sweep only drops windows that are over.
+        limiter.last_sweep = start;
+        assert!(limiter.charge_at("carol", 1, start + window + window / 2).is_ok());
+        assert_eq!(limiter.usage.len(), 2);
+        assert!(!limiter.usage.contains_key("bob"));
+    }
+}
@@ -0,0 +1,292 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    mysql::{MySqlConnection, MySqlPool},
+    FromRow,
+};
+
+use crate::{crypto, message::MessageKind};
+
+pub const GROUP_ID_LENGTH: usize = 16;
+
+/// A group as stored by the server. The group's name, avatar and other
+/// attributes are only known to its members and kept in the encrypted
+/// `metadata` blob.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub group_id: Vec<u8>,
+    pub metadata: Vec<u8>,
+    pub members: Vec<GroupMember>,
+}
+
+#[derive(Debug, Default, Clone, FromRow, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub username: String,
+    pub admin: bool,
+}
+
+/// Outcome of [`Group::add_member`] and [`Group::remove_member`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembershipChange {
+    Done,
+    /// The user doesn't exist, or isn't a member of the group when removing them.
+    UserNotFound,
+    /// The change would leave the remaining members without an admin.
+    LastAdmin,
+}
+
+impl Group {
+    /// Creates a new group administered by `creator`. Fails with
+    /// [`sqlx::Error::RowNotFound`] if any of `members` doesn't exist.
+    pub async fn create(
+        db: &MySqlPool,
+        creator: &str,
+        metadata: Vec<u8>,
+        members: &[String],
+    ) -> Result<Group, sqlx::Error> {
+        let group_id = crypto::random_bytes(GROUP_ID_LENGTH);
+        let mut tx = db.begin().await?;
+
+        sqlx::query("INSERT INTO chat_group(group_id, metadata, created_at) VALUES (?, ?, ?)")
+            .bind(&group_id)
+            .bind(&metadata)
+            .bind(crate::unix_timestamp())
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("INSERT INTO group_member(group_id, username, admin) VALUES (?, ?, TRUE)")
+            .bind(&group_id)
+            .bind(creator)
+            .execute(&mut *tx)
+            .await?;
+
+        let mut members = members.iter().filter(|m| m.as_str() != creator).collect::<Vec<_>>();
+        members.sort();
+        members.dedup();
+
+        for member in members {
+            let added = sqlx::query(
+                "INSERT INTO group_member(group_id, username, admin) \
+                 SELECT ?, username, FALSE FROM user WHERE username = ?",
+            )
+            .bind(&group_id)
+            .bind(member)
+            .execute(&mut *tx)
+            .await?;
+
+            if added.rows_affected() == 0 {
+                return Err(sqlx::Error::RowNotFound);
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(Group::find(db, &group_id)
+            .await?
+            .expect("Expected newly created group to exist"))
+    }
+
+    pub async fn find(db: &MySqlPool, group_id: &[u8]) -> Result<Option<Group>, sqlx::Error> {
+        let metadata: Option<(Vec<u8>,)> =
+            sqlx::query_as("SELECT metadata FROM chat_group WHERE group_id = ?")
+                .bind(group_id)
+                .fetch_optional(db)
+                .await?;
+
+        let Some((metadata,)) = metadata else {
+            return Ok(None);
+        };
+
+        let members = sqlx::query_as(
+            "SELECT username, admin FROM group_member WHERE group_id = ? ORDER BY username",
+        )
+        .bind(group_id)
+        .fetch_all(db)
+        .await?;
+
+        Ok(Some(Group {
+            group_id: group_id.to_vec(),
+            metadata,
+            members,
+        }))
+    }
+
+    pub fn member(&self, username: &str) -> Option<&GroupMember> {
+        self.members.iter().find(|m| m.username == username)
+    }
+
+    /// Adds `username` to the group, or updates their admin flag if they
+    /// already are a member. The group's only admin can't be demoted.
+    pub async fn add_member(&self, db: &MySqlPool, username: &str, admin: bool) -> Result<MembershipChange, sqlx::Error> {
+        let mut tx = db.begin().await?;
+        let members = self.lock_members(&mut tx).await?;
+        if !admin && is_last_admin(&members, username) {
+            return Ok(MembershipChange::LastAdmin);
+        }
+
+        let added = sqlx::query(
+            "INSERT INTO group_member(group_id, username, admin) \
+             SELECT ?, username, ? FROM user WHERE username = ? \
+             ON DUPLICATE KEY UPDATE admin = VALUES(admin)",
+        )
+        .bind(&self.group_id)
+        .bind(admin)
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(if added.rows_affected() > 0 { MembershipChange::Done } else { MembershipChange::UserNotFound })
+    }
+
+    /// Removes `username` from the group. The group's only admin can't be
+    /// removed while there are other members, and the group is deleted once
+    /// its last member is gone.
+    pub async fn remove_member(&self, db: &MySqlPool, username: &str) -> Result<MembershipChange, sqlx::Error> {
+        let mut tx = db.begin().await?;
+        let members = self.lock_members(&mut tx).await?;
+        if !members.iter().any(|m| m.username == username) {
+            return Ok(MembershipChange::UserNotFound);
+        }
+        if members.len() > 1 && is_last_admin(&members, username) {
+            return Ok(MembershipChange::LastAdmin);
+        }
+
+        sqlx::query("DELETE FROM group_member WHERE group_id = ? AND username = ?")
+            .bind(&self.group_id)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+
+        if members.len() == 1 {
+            sqlx::query("DELETE FROM chat_group WHERE group_id = ?")
+                .bind(&self.group_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(MembershipChange::Done)
+    }
+
+    /// Removes `username`, whose account is being deleted, from all of its
+    /// groups. Meant to run in the transaction that deletes the account. Where
+    /// it was the only admin, the remaining member whose name sorts first
+    /// becomes admin, and groups left without members are deleted.
+    pub async fn remove_deleted_member(conn: &mut MySqlConnection, username: &str) -> Result<(), sqlx::Error> {
+        let groups: Vec<(Vec<u8>,)> = sqlx::query_as("SELECT group_id FROM group_member WHERE username = ?")
+            .bind(username)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        for (group_id,) in &groups {
+            let members: Vec<GroupMember> =
+                sqlx::query_as("SELECT username, admin FROM group_member WHERE group_id = ? FOR UPDATE")
+                    .bind(group_id)
+                    .fetch_all(&mut *conn)
+                    .await?;
+
+            sqlx::query("DELETE FROM group_member WHERE group_id = ? AND username = ?")
+                .bind(group_id)
+                .bind(username)
+                .execute(&mut *conn)
+                .await?;
+
+            if let Some(successor) = successor_admin(&members, username) {
+                sqlx::query("UPDATE group_member SET admin = TRUE WHERE group_id = ? AND username = ?")
+                    .bind(group_id)
+                    .bind(successor)
+                    .execute(&mut *conn)
+                    .await?;
+            } else if members.iter().all(|m| m.username == username) {
+                sqlx::query("DELETE FROM chat_group WHERE group_id = ?")
+                    .bind(group_id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Current members of the group, locked until the end of the transaction
+    /// so that concurrent changes can't leave the group without an admin.
+    async fn lock_members(&self, conn: &mut MySqlConnection) -> Result<Vec<GroupMember>, sqlx::Error> {
+        sqlx::query_as("SELECT username, admin FROM group_member WHERE group_id = ? FOR UPDATE")
+            .bind(&self.group_id)
+            .fetch_all(conn)
+            .await
+    }
+
+    /// Queues a copy of `content` for every member of the group except `sender`.
+    pub async fn fan_out(&self, db: &MySqlPool, sender: &str, content: &[u8]) -> Result<u64, sqlx::Error> {
+        let queued = sqlx::query(
+            "INSERT INTO message(recipient, sender, kind, content) \
+             SELECT username, ?, ?, ? FROM group_member WHERE group_id = ? AND username <> ?",
+        )
+        .bind(sender)
+        .bind(MessageKind::Group)
+        .bind(content)
+        .bind(&self.group_id)
+        .bind(sender)
+        .execute(db)
+        .await?;
+
+        Ok(queued.rows_affected())
+    }
+}
+
+/// Whether `username` is the only admin among `members`.
+fn is_last_admin(members: &[GroupMember], username: &str) -> bool {
+    let mut admins = members.iter().filter(|m| m.admin);
+    admins.next().is_some_and(|m| m.username == username) && admins.next().is_none()
+}
+
+/// The member to promote when `username`, the only admin among `members`,
+/// leaves the group. `None` if other admins remain or nobody else is left.
+fn successor_admin<'a>(members: &'a [GroupMember], username: &str) -> Option<&'a str> {
+    if !is_last_admin(members, username) {
+        return None;
+    }
+    members
+        .iter()
+        .map(|m| m.username.as_str())
+        .filter(|&m| m != username)
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(username: &str, admin: bool) -> GroupMember {
+        GroupMember {
+            username: username.to_string(),
+            admin,
+        }
+    }
+
+    #[test]
+    fn last_admin() {
+        let members = [member("alice", true), member("bob", false)];
+        assert!(is_last_admin(&members, "alice"));
+        assert!(!is_last_admin(&members, "bob"));
+
+        let members = [member("alice", true), member("bob", true)];
+        assert!(!is_last_admin(&members, "alice"));
+        assert!(!is_last_admin(&members, "bob"));
+
+        assert!(!is_last_admin(&[member("alice", false)], "alice"));
+    }
+
+    #[test]
+    fn successor_of_last_admin() {
+        let members = [member("carol", false), member("alice", true), member("bob", false)];
+        assert_eq!(successor_admin(&members, "alice"), Some("bob"));
+        assert_eq!(successor_admin(&members, "bob"), None);
+
+        let members = [member("alice", true), member("bob", true), member("carol", false)];
+        assert_eq!(successor_admin(&members, "alice"), None);
+
+        assert_eq!(successor_admin(&[member("alice", true)], "alice"), None);
+    }
+}
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    mysql::{MySqlConnection, MySqlPool},
+    FromRow,
+};
+use std::time::Duration;
+
+use crate::{
+    crypto::{PublicKey, SigningKey},
+    transparency::InclusionProof,
+};
+
+/// Identity key changes younger than this are flagged in prekey bundles so that
+/// clients can warn about a changed safety number.
+pub const RECENT_CHANGE_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, FromRow)]
+pub struct IdentityKeyChange {
+    pub username: String,
+    pub identity_key: PublicKey,
+    pub changed_at: u64,
+}
+
+impl IdentityKeyChange {
+    /// Records `identity_key` as the current key of `username` unless it already is.
+    /// Returns the entry that was added, if any. Meant to run in the same
+    /// transaction as the write that changes the key.
+    pub async fn record(
+        conn: &mut MySqlConnection,
+        username: &str,
+        identity_key: &PublicKey,
+    ) -> Result<Option<IdentityKeyChange>, sqlx::Error> {
+        let latest: Option<IdentityKeyChange> = sqlx::query_as(
+            "SELECT username, identity_key, changed_at FROM identity_key_history \
+             WHERE username = ? ORDER BY history_id DESC LIMIT 1 FOR UPDATE",
+        )
+        .bind(username)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        if let Some(latest) = latest {
+            if latest.identity_key.as_ref() == identity_key.as_ref() {
+                return Ok(None);
+            }
+        }
+
+        let change = IdentityKeyChange {
+            username: username.to_string(),
+            identity_key: identity_key.clone(),
+            changed_at: crate::unix_timestamp(),
+        };
+
+        sqlx::query(
+            "INSERT INTO identity_key_history(username, identity_key, changed_at) VALUES (?, ?, ?)",
+        )
+        .bind(&change.username)
+        .bind(&change.identity_key)
+        .bind(change.changed_at)
+        .execute(conn)
+        .await?;
+
+        Ok(Some(change))
+    }
+
+    pub async fn latest(
+        db: &MySqlPool,
+        username: &str,
+    ) -> Result<Option<IdentityKeyChange>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT username, identity_key, changed_at FROM identity_key_history \
+             WHERE username = ? ORDER BY history_id DESC LIMIT 1",
+        )
+        .bind(username)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Returns the time of the last identity key change of `username` if it
+    /// replaced an earlier key within [`RECENT_CHANGE_WINDOW`]. The initial key
+    /// of an account is not considered a change.
+    pub async fn recent_change(db: &MySqlPool, username: &str) -> Result<Option<u64>, sqlx::Error> {
+        let history: Vec<IdentityKeyChange> = sqlx::query_as(
+            "SELECT username, identity_key, changed_at FROM identity_key_history \
+             WHERE username = ? ORDER BY history_id DESC LIMIT 2",
+        )
+        .bind(username)
+        .fetch_all(db)
+        .await?;
+
+        Ok(recent_change_at(&history, crate::unix_timestamp()))
+    }
+}
+
+/// `history` holds the newest entries first, as in [`IdentityKeyChange::recent_change`].
+fn recent_change_at(history: &[IdentityKeyChange], now: u64) -> Option<u64> {
+    let cutoff = now.saturating_sub(RECENT_CHANGE_WINDOW.as_secs());
+    match history {
+        [latest, _] if latest.changed_at >= cutoff => Some(latest.changed_at),
+        _ => None,
+    }
+}
+
+/// A user's current identity key together with the time it last changed,
+/// signed by the server's [`SigningKey`].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SignedIdentityKey {
+    pub username: String,
+    pub identity_key: PublicKey,
+    pub changed_at: u64,
+    pub signature: Vec<u8>,
+    /// Proof that this binding is part of the key transparency log.
+    pub inclusion_proof: Option<InclusionProof>,
+}
+
+impl SignedIdentityKey {
+    pub fn new(change: IdentityKeyChange, signing_key: &SigningKey) -> Self {
+        let mut key = Self {
+            username: change.username,
+            identity_key: change.identity_key,
+            changed_at: change.changed_at,
+            signature: vec![],
+            inclusion_proof: None,
+        };
+        key.signature = signing_key.sign(&key.signed_data());
+        key
+    }
+
+    /// The bytes covered by `signature`: the username, a NUL byte, the identity
+    /// key and the big endian `changed_at` timestamp.
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(self.username.as_bytes());
+        data.push(0);
+        data.extend_from_slice(self.identity_key.as_ref());
+        data.extend_from_slice(&self.changed_at.to_be_bytes());
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(changed_at: u64) -> IdentityKeyChange {
+        IdentityKeyChange {
+            username: "alice".to_string(),
+            identity_key: PublicKey::default(),
+            changed_at,
+        }
+    }
+
+    #[test]
+    fn initial_key_is_not_a_change() {
+        let now = 1_000_000_000;
+        assert_eq!(recent_change_at(&[change(now)], now), None);
+    }
+
+    #[test]
+    fn key_of_reregistered_account_is_a_change() {
+        // Deleting the account keeps its history, so the key registered under
+        // the same name afterwards follows the key of the deleted account.
+        let now = 1_000_000_000;
+        let history = [change(now - 60), change(now - 3600)];
+        assert_eq!(recent_change_at(&history, now), Some(now - 60));
+    }
+
+    #[test]
+    fn old_changes_are_not_recent() {
+        let now = 1_000_000_000;
+        let changed_at = now - RECENT_CHANGE_WINDOW.as_secs() - 1;
+        let history = [change(changed_at), change(0)];
+        assert_eq!(recent_change_at(&history, now), None);
+    }
+}
@@ -1,5 +1,17 @@
+pub mod attachment;
+pub mod audit;
+pub mod config;
 pub mod crypto;
+pub mod discovery;
+pub mod group;
+pub mod identity;
+pub mod logging;
+pub mod message;
+pub mod profile;
+pub mod registration;
+pub mod sealed_sender;
 pub mod server;
+pub mod transparency;
 pub mod tests;
 pub mod user;
 
@@ -8,10 +20,32 @@ macro_rules! routes {
     ( $init: expr, $($k: expr => $v: ident),* ) => {{
         let mut map = std::collections::HashMap::new();
         $({
-            log::debug!("Constructing handler for discriminant: {}", $k as u8);
+            tracing::debug!("Constructing handler for discriminant: {}", $k as u8);
             let value: Box<dyn arke::server::command::CommandHandler> = Box::new($v::new($init));
             map.insert($k(Default::default()).discriminant(), value);
         })*
         map
     }};
 }
+
+/// Seconds since the unix epoch.
+pub fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("System time is before the unix epoch")
+        .as_secs()
+}
+
+/// Clients are told apart by their address, or by their /64 network for IPv6
+/// where a single client usually controls a whole one.
+pub fn peer_key(peer: std::net::IpAddr) -> std::net::IpAddr {
+    use std::net::IpAddr;
+
+    match peer {
+        IpAddr::V4(_) => peer,
+        IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6((u128::from(addr) & !u128::from(u64::MAX)).into()),
+        },
+    }
+}
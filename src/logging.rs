@@ -0,0 +1,86 @@
+//! Log output. Events are written to stdout together with the spans they
+//! happened in, e.g. the connection and command being handled.
+
+use serde::Deserialize;
+use std::{fmt, time::SystemTime};
+use tracing::{level_filters::LevelFilter, Event, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    fmt::{
+        format::{self, FormatEvent, FormatFields},
+        FmtContext, FormattedFields,
+    },
+    registry::LookupSpan,
+    util::{SubscriberInitExt, TryInitError},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `<time>[<target>][<level>] <spans>: <message>` lines.
+    #[default]
+    Text,
+    /// One JSON object per line, including the fields of the current span and
+    /// all of its parents.
+    Json,
+}
+
+/// Installs the global subscriber. Records of crates using `log` are logged too.
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), TryInitError> {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_writer(std::io::stdout);
+
+    match format {
+        LogFormat::Text => builder.event_format(TextFormat).finish().try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .finish()
+            .try_init(),
+    }
+}
+
+/// Formats events as `2023-07-01T12:00:00Z[arke::server][INFO] connection{peer=[::1]:50000}: message`.
+struct TextFormat;
+
+impl<S, N> FormatEvent<S, N> for TextFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        write!(
+            writer,
+            "{}[{}][{}] ",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            metadata.target(),
+            metadata.level()
+        )?;
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                write!(writer, "{}", span.name())?;
+                let extensions = span.extensions();
+                if let Some(fields) = extensions.get::<FormattedFields<N>>() {
+                    if !fields.is_empty() {
+                        write!(writer, "{{{fields}}}")?;
+                    }
+                }
+                write!(writer, ": ")?;
+            }
+        }
+
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
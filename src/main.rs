@@ -1,51 +1,199 @@
-use arke::{server::{command::{ArkeHello, ArkeCommand}, ArkeServer, db::Entity}, user::User};
-use log::warn;
-use arke::server::{state::State, command::CommandError};
+use arke::attachment::{self, AttachmentStore};
+use arke::audit::{self, AuditEvent};
+use arke::config::{Config, ConfigOverrides};
+use arke::crypto::{self, SigningKey};
+use arke::discovery;
+use arke::group::{Group, MembershipChange};
+use arke::identity::{IdentityKeyChange, SignedIdentityKey};
+use arke::logging;
+use arke::message::{Message, MessageKind};
+use arke::profile::Profile;
+use arke::registration::{RegistrationChallenge, RegistrationGate};
+use arke::sealed_sender::{self, SenderCertificate};
+use arke::server::command::{
+    AddGroupMember, ArkeCommand, ArkeHello, AttachmentChunk, AttachmentData, AuthChallenge, Authenticate,
+    BeginUpload, CommandError, CreateGroup, DeleteAccount, DiscoverContacts, DownloadAttachment, FinishUpload,
+    GetConsistencyProof, GetGroup, GetIdentityKey, GetPrekeyBundle, GetProfile, GroupMessage, OutgoingMessage,
+    RemoveGroupMember, SetProfile, SetUnidentifiedAccess, UploadStarted,
+};
+use arke::server::connection::Connection;
+use arke::server::db::Entity;
+use arke::server::state::State;
+use arke::server::tls::TlsError;
+use arke::server::{ArkeServer, Listener};
+use arke::transparency::KeyLog;
+use arke::user::{self, NewUser, User};
+use tracing::warn;
+use clap::Parser;
 use macros::command_handler;
-use openssl::ec::EcKey;
-use std::{env, net::Ipv4Addr, str::FromStr, time::SystemTime, sync::Arc};
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use std::{path::PathBuf, time::Duration, sync::Arc};
 use tokio::sync::Mutex;
 
-#[cfg(debug_assertions)]
-const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
-#[cfg(not(debug_assertions))]
-const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Info;
-
-fn setup_logger() -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                humantime::format_rfc3339_seconds(SystemTime::now()),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(LOG_LEVEL)
-        .chain(std::io::stdout())
-        .apply()?;
+/// Arke messaging server
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Configuration file, defaults to arke.toml if it exists
+    #[arg(short, long, env = "ARKE_CONFIG")]
+    config: Option<PathBuf>,
+    /// Validate the configuration and exit
+    #[arg(long)]
+    check_config: bool,
+    #[command(flatten)]
+    overrides: ConfigOverrides,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Administrative tasks that run against the database and exit instead of starting the server
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Inspect and manage accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Inspect one-time prekeys
+    #[command(subcommand)]
+    Prekeys(PrekeysCommand),
+    /// Manage queued messages
+    #[command(subcommand)]
+    Messages(MessagesCommand),
+    /// Inspect the security audit log
+    #[command(subcommand)]
+    Audit(AuditCommand),
+}
+
+#[derive(clap::Subcommand)]
+enum UserCommand {
+    /// List all accounts
+    List,
+    /// Show the keys and queued messages of an account
+    Show { username: String },
+    /// Prevent an account from authenticating
+    Disable { username: String },
+    /// Allow a disabled account to authenticate again
+    Enable { username: String },
+}
+
+#[derive(clap::Subcommand)]
+enum PrekeysCommand {
+    /// Show how many one-time prekeys are left
+    Stats,
+}
+
+#[derive(clap::Subcommand)]
+enum MessagesCommand {
+    /// Delete queued messages that haven't been picked up in time
+    Purge {
+        /// Minimum age of the messages to delete, e.g. "30days"
+        #[arg(long, value_parser = humantime::parse_duration)]
+        older_than: Duration,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum AuditCommand {
+    /// Check that no entry of the audit log has been altered or removed
+    Verify,
+}
+
+async fn run_command(command: Command, db: &sqlx::mysql::MySqlPool) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::User(UserCommand::List) => {
+            for user in User::list(db).await? {
+                let disabled = if user.disabled { " (disabled)" } else { "" };
+                println!("{}\t{} prekeys{disabled}", user.username, user.one_time_prekeys);
+            }
+        }
+        Command::User(UserCommand::Show { username }) => {
+            let username = cli_username(&username);
+            let Some(found) = User::find(db, &username).await? else {
+                return Err(format!("No such user: {username}").into());
+            };
+            println!("username: {}", found.username);
+            println!("disabled: {}", User::is_disabled(db, &username).await?);
+            println!("identity key: {}", crypto::to_hex(found.identity_key.as_ref()));
+            if let Some(change) = IdentityKeyChange::latest(db, &username).await? {
+                println!("identity key changed: {}", humantime::format_rfc3339_seconds(std::time::UNIX_EPOCH + Duration::from_secs(change.changed_at)));
+            }
+            println!("one-time prekeys: {}", found.one_time_prekeys().len());
+            println!("queued messages: {}", Message::count_queued(db, &username).await?);
+        }
+        Command::User(UserCommand::Disable { username }) => set_user_disabled(db, &username, true).await?,
+        Command::User(UserCommand::Enable { username }) => set_user_disabled(db, &username, false).await?,
+        Command::Prekeys(PrekeysCommand::Stats) => {
+            let stats = User::prekey_stats(db).await?;
+            println!("users: {}", stats.users);
+            println!("one-time prekeys available: {}", stats.available);
+            println!("users without one-time prekeys: {}", stats.exhausted);
+        }
+        Command::Messages(MessagesCommand::Purge { older_than }) => {
+            let purged = Message::purge_older_than(db, older_than).await?;
+            println!("Purged {purged} messages");
+        }
+        Command::Audit(AuditCommand::Verify) => {
+            let (entries, head) = audit::verify(db).await?;
+            println!("Audit log is intact: {entries} entries, head {}", crypto::to_hex(&head));
+        }
+    }
     Ok(())
 }
 
+/// Disables or re-enables an account and records it in the audit log.
+async fn set_user_disabled(db: &sqlx::mysql::MySqlPool, username: &str, disabled: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let username = &cli_username(username);
+    let event = if disabled { AuditEvent::AccountDisabled } else { AuditEvent::AccountEnabled };
+    let mut tx = db.begin().await?;
+    if !User::set_disabled(&mut tx, username, disabled).await? {
+        return Err(format!("No such user: {username}").into());
+    }
+    audit::append(&mut tx, &event, username, None).await?;
+    tx.commit().await?;
+    println!("{} {username}", if disabled { "Disabled" } else { "Enabled" });
+    Ok(())
+}
+
+/// Usernames given on the command line, normalized like those sent by clients.
+/// Names that aren't valid are used as given, so that accounts created before
+/// usernames were validated can still be managed.
+fn cli_username(username: &str) -> String {
+    user::normalize_username(username).unwrap_or_else(|_| username.to_string())
+}
+
+/// Records an event that isn't part of any other change in the audit log. The
+/// command fails if the event can't be recorded.
+async fn record_audit_event(db: &sqlx::MySqlPool, event: &AuditEvent, username: &str, connection: &Connection) -> Result<(), ArkeCommand> {
+    audit::record(db, event, username, Some(connection.peer_addr)).await.map(|_| ()).map_err(|err| {
+        tracing::error!("Couldn't record {} of {username} in the audit log: {err:?}", event.name());
+        CommandError::ServerError {
+            msg: "Couldn't record audit event!".to_string()
+        }.into()
+    })
+}
+
+/// Usernames sent by clients as they are logged: normalized, or cut to the
+/// maximum length if they aren't valid usernames at all.
+fn logged_username(username: &str) -> String {
+    user::normalize_username(username).unwrap_or_else(|_| username.chars().take(user::USERNAME_MAX_LENGTH).collect())
+}
+
 #[command_handler(
-    state = "state",
+    state = "_state",
     command(
-        ArkeCommand::Hello(ArkeHello { version: (major, minor, _patch) }), 
+        ArkeCommand::Hello(ArkeHello { version: (major, minor, patch) }), 
         CommandError::ServerError { 
             msg: "Invalid command".to_string() 
         }.into()
     )
 )]
-async fn hello(state: State, command: ArkeCommand) -> ArkeCommand {
+async fn hello(_state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
     let hello = ArkeHello::default();
     let (server_major, server_minor, _) = hello.version; 
     
     if server_major != major || server_minor != minor {
         CommandError::ServerError { msg: "Server and client have a version mismatch!".to_string() }.into()
     } else {
-        state.handshake = true;
+        connection.handshake = true;
+        connection.version = Some((major, minor, patch));
         ArkeCommand::Hello(hello)
     }
 }
@@ -66,32 +214,780 @@ async fn insert_prekeys(_state: State, command: ArkeCommand) -> ArkeCommand {
         msg: "Invalid command".to_string()
     }.into()
 ))]
-async fn create_user(state: State, command: ArkeCommand) -> ArkeCommand {
-    let identity_key = if let Ok(key) = new_user.identity_key.ec_key() {
-        key
+async fn create_user(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    if let Some(gate) = state.registration.as_mut() {
+        if let Err(err) = gate.verify(&new_user.username, new_user.proof_of_work.as_ref()) {
+            return ArkeCommand::Error(err);
+        }
+    }
+
+    let new_user = match user::normalize_username(&new_user.username) {
+        Ok(username) => NewUser { username, ..new_user },
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    if new_user.identity_key.ec_key().is_err() {
+        return ArkeCommand::Error(CommandError::InvalidKey);
+    }
+    
+    if !new_user.identity_key.verify(new_user.signed_prekey.as_ref(), &new_user.prekey_signature) {
+        let event = AuditEvent::InvalidSignature { msg: "Prekey signature is invalid".to_string() };
+        if let Err(response) = record_audit_event(&state.db, &event, &new_user.username, connection).await {
+            return response;
+        }
+        return ArkeCommand::Error(CommandError::InvalidSignature { msg: "Prekey signature is invalid".to_string() });
+    }
+
+    match User::is_tombstoned(&state.db, &new_user.username).await {
+        Ok(false) => {}
+        Ok(true) => return ArkeCommand::Error(CommandError::UsernameTaken),
+        Err(err) => {
+            tracing::error!("Couldn't check username tombstone: {err:?}");
+            return CommandError::ServerError {
+                msg: "Couldn't create new user!".to_string()
+            }.into();
+        }
+    }
+    
+    let user = User::from(new_user);
+    // The account and its first identity key are stored together, so that
+    // there never is an account without a recorded key.
+    let created: Result<Option<IdentityKeyChange>, sqlx::Error> = async {
+        let mut tx = state.db.begin().await?;
+        user.create(&mut tx).await?;
+        let change = IdentityKeyChange::record(&mut tx, &user.username, &user.identity_key).await?;
+        audit::append(&mut tx, &AuditEvent::AccountCreated, &user.username, Some(connection.peer_addr)).await?;
+        if let Some(change) = &change {
+            state.key_log.insert(&mut tx, &change.username, &change.identity_key).await?;
+            let event = AuditEvent::IdentityKeyChanged { identity_key: change.identity_key.as_ref().to_vec() };
+            audit::append(&mut tx, &event, &change.username, Some(connection.peer_addr)).await?;
+        }
+        let event = AuditEvent::PrekeysUploaded {
+            signed_prekey: user.signed_prekey.as_ref().to_vec(),
+            one_time_prekeys: user.one_time_prekeys().len(),
+        };
+        audit::append(&mut tx, &event, &user.username, Some(connection.peer_addr)).await?;
+        tx.commit().await?;
+        Ok(change)
+    }.await;
+
+    match created {
+        Ok(change) => {
+            if let Some(change) = change {
+                tracing::info!("Recorded identity key for {} at {}", change.username, change.changed_at);
+                state.key_log.push(&change.username, &change.identity_key);
+            }
+            if let Some(gate) = state.registration.as_mut() {
+                gate.record_registration();
+            }
+            ArkeCommand::Success
+        }
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            ArkeCommand::Error(CommandError::UsernameTaken)
+        }
+        Err(err) => {
+            tracing::error!("Couldn't create new user: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't create new user!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::RegistrationChallenge(_),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn registration_challenge(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    match state.registration.as_mut() {
+        Some(gate) => match gate.issue(connection.peer_addr.ip()) {
+            Ok(challenge) => ArkeCommand::RegistrationChallenge(challenge),
+            Err(err) => ArkeCommand::Error(err),
+        },
+        None => ArkeCommand::RegistrationChallenge(RegistrationChallenge::default()),
+    }
+}
+
+#[command_handler(state = "_state", command(
+    ArkeCommand::AuthChallenge(_),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn auth_challenge(_state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let nonce = crypto::random_bytes(32);
+    connection.challenge = Some(nonce.clone());
+    ArkeCommand::AuthChallenge(AuthChallenge { nonce })
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::Authenticate(Authenticate { username, signature }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn authenticate(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    // Until the account is found, failures are recorded under the name the
+    // client sent, normalized or cut short, see `logged_username`.
+    let failed = |msg: &str| AuditEvent::AuthenticationFailed { msg: msg.to_string() };
+    let logged_username = logged_username(&username);
+
+    let Some(challenge) = connection.challenge.take() else {
+        let event = failed("No challenge was requested");
+        if let Err(response) = record_audit_event(&state.db, &event, &logged_username, connection).await {
+            return response;
+        }
+        return ArkeCommand::Error(CommandError::AuthenticationFailed);
+    };
+
+    let user = match User::find(&state.db, &username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            let event = match state.unknown_user_failures.admit(connection.peer_addr.ip()) {
+                Some(0) => Some(failed("Unknown user")),
+                Some(left_out) => Some(failed(&format!(
+                    "Unknown user, {left_out} earlier failures for unknown users from this client not recorded"
+                ))),
+                None => None,
+            };
+            if let Some(event) = event {
+                if let Err(response) = record_audit_event(&state.db, &event, &logged_username, connection).await {
+                    return response;
+                }
+            }
+            return ArkeCommand::Error(CommandError::AuthenticationFailed);
+        }
+        Err(err) => {
+            tracing::error!("Couldn't look up user: {err:?}");
+            return CommandError::ServerError {
+                msg: "Couldn't authenticate user!".to_string()
+            }.into();
+        }
+    };
+
+    match User::is_disabled(&state.db, &user.username).await {
+        Ok(false) => {}
+        Ok(true) => {
+            if let Err(response) = record_audit_event(&state.db, &failed("Account is disabled"), &user.username, connection).await {
+                return response;
+            }
+            return ArkeCommand::Error(CommandError::AuthenticationFailed);
+        }
+        Err(err) => {
+            tracing::error!("Couldn't look up user: {err:?}");
+            return CommandError::ServerError {
+                msg: "Couldn't authenticate user!".to_string()
+            }.into();
+        }
+    }
+
+    if user.identity_key.verify(&AuthChallenge { nonce: challenge }.signed_data(), &signature) {
+        tracing::info!("Connection {} authenticated as {username}", connection.peer_addr);
+        connection.user = Some(user.username);
+        ArkeCommand::Success
     } else {
+        tracing::warn!("Failed authentication attempt for {} from {}", user.username, connection.peer_addr);
+        let event = failed("Challenge signature is invalid");
+        match record_audit_event(&state.db, &event, &user.username, connection).await {
+            Ok(()) => ArkeCommand::Error(CommandError::AuthenticationFailed),
+            Err(response) => response,
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::DeleteAccount(DeleteAccount { notify }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn delete_account(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username.to_string(),
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    let deleted: Result<Option<user::DeletedAccount>, sqlx::Error> = async {
+        let mut tx = state.db.begin().await?;
+        let deleted = User::delete(&mut tx, &username, state.username_cooldown, &notify).await?;
+        if deleted.is_some() {
+            audit::append(&mut tx, &AuditEvent::AccountDeleted, &username, Some(connection.peer_addr)).await?;
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }.await;
+
+    match deleted {
+        Ok(deleted) => {
+            tracing::info!("Deleted account {username}");
+            for attachment_id in deleted.map(|d| d.attachments).unwrap_or_default() {
+                state.attachments.remove_files(&attachment_id).await;
+            }
+            connection.user = None;
+            ArkeCommand::Success
+        }
+        Err(err) => {
+            tracing::error!("Couldn't delete account {username}: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't delete account!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::GetIdentityKey(GetIdentityKey { username }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn get_identity_key(state: State, command: ArkeCommand) -> ArkeCommand {
+    // The key history outlives deleted accounts, only serve keys of existing ones.
+    match User::find(&state.db, &username).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return ArkeCommand::Error(CommandError::UserNotFound),
+        Err(err) => {
+            tracing::error!("Couldn't look up user {username}: {err:?}");
+            return CommandError::ServerError {
+                msg: "Couldn't look up identity key!".to_string()
+            }.into();
+        }
+    }
+
+    match IdentityKeyChange::latest(&state.db, &username).await {
+        Ok(Some(change)) => {
+            let mut key = SignedIdentityKey::new(change, &state.signing_key);
+            key.inclusion_proof = state.key_log
+                .latest_index(&key.username)
+                .and_then(|index| state.key_log.inclusion_proof(index, &state.signing_key));
+            ArkeCommand::IdentityKey(key)
+        }
+        Ok(None) => ArkeCommand::Error(CommandError::UserNotFound),
+        Err(err) => {
+            tracing::error!("Couldn't look up identity key of {username}: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't look up identity key!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::GetPrekeyBundle(GetPrekeyBundle { username }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn get_prekey_bundle(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    if let Err(err) = connection.username() {
+        return ArkeCommand::Error(err);
+    }
+
+    let bundle = match User::find(&state.db, &username).await {
+        Ok(Some(user)) => user.take_prekey_bundle(&state.db).await,
+        Ok(None) => return ArkeCommand::Error(CommandError::UserNotFound),
+        Err(err) => Err(err),
+    };
+
+    match bundle {
+        Ok(mut bundle) => {
+            bundle.inclusion_proof = state.key_log
+                .latest_index(&bundle.username)
+                .and_then(|index| state.key_log.inclusion_proof(index, &state.signing_key));
+            ArkeCommand::PrekeyBundle(bundle)
+        }
+        Err(err) => {
+            tracing::error!("Couldn't build prekey bundle for {username}: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't build prekey bundle!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::GetServerKey(_),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn get_server_key(state: State, command: ArkeCommand) -> ArkeCommand {
+    ArkeCommand::ServerKey(state.signing_key.public_key())
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::GetTreeHead(_),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn get_tree_head(state: State, command: ArkeCommand) -> ArkeCommand {
+    ArkeCommand::TreeHead(state.key_log.tree_head(&state.signing_key))
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::GetConsistencyProof(GetConsistencyProof { first, second }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn get_consistency_proof(state: State, command: ArkeCommand) -> ArkeCommand {
+    match state.key_log.consistency_proof(first, second) {
+        Some(proof) => ArkeCommand::ConsistencyProof(proof),
+        None => ArkeCommand::Error(CommandError::InvalidRequest {
+            msg: format!("No consistency proof between tree sizes {first} and {second}")
+        }),
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::SetUnidentifiedAccess(SetUnidentifiedAccess { access_key }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn set_unidentified_access(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    if access_key.is_empty() || access_key.len() > sealed_sender::MAX_ACCESS_KEY_LENGTH {
         return ArkeCommand::Error(CommandError::InvalidKey);
+    }
+
+    if let Err(err) = sealed_sender::set_access_key(&state.db, username, &access_key).await {
+        tracing::error!("Couldn't set unidentified access key of {username}: {err:?}");
+        CommandError::ServerError {
+            msg: "Couldn't set unidentified access key!".to_string()
+        }.into()
+    } else {
+        ArkeCommand::Success
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::GetSenderCertificate(_),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn get_sender_certificate(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
     };
-    
-    if let Ok(sig) = openssl::ecdsa::EcdsaSig::from_der(&new_user.prekey_signature) {
-        if let Ok(true) = sig.verify(new_user.signed_prekey.as_ref(), identity_key.as_ref()) {
-        } else {
-            return ArkeCommand::Error(CommandError::InvalidSignature { msg: "Prekey signature is invalid".to_string() }).into();
+
+    match User::find(&state.db, username).await {
+        Ok(Some(user)) => ArkeCommand::SenderCertificate(SenderCertificate::new(
+            user.username,
+            user.identity_key,
+            &state.signing_key,
+        )),
+        Ok(None) => ArkeCommand::Error(CommandError::UserNotFound),
+        Err(err) => {
+            tracing::error!("Couldn't look up user {username}: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't issue sender certificate!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::SendMessage(OutgoingMessage { recipient, content, access_key }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn send_message(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let message = if let Some(access_key) = access_key {
+        match sealed_sender::verify_access_key(&state.db, &recipient, &access_key).await {
+            Ok(true) => Message { recipient, sender: None, kind: MessageKind::Sealed, content },
+            Ok(false) => return ArkeCommand::Error(CommandError::AuthenticationFailed),
+            Err(err) => {
+                tracing::error!("Couldn't check unidentified access key: {err:?}");
+                return CommandError::ServerError {
+                    msg: "Couldn't send message!".to_string()
+                }.into();
+            }
+        }
+    } else {
+        let sender = match connection.username() {
+            Ok(username) => username.to_string(),
+            Err(err) => return ArkeCommand::Error(err),
+        };
+
+        match User::find(&state.db, &recipient).await {
+            Ok(Some(_)) => Message { recipient, sender: Some(sender), kind: MessageKind::Direct, content },
+            Ok(None) => return ArkeCommand::Error(CommandError::UserNotFound),
+            Err(err) => {
+                tracing::error!("Couldn't look up recipient {recipient}: {err:?}");
+                return CommandError::ServerError {
+                    msg: "Couldn't send message!".to_string()
+                }.into();
+            }
         }
+    };
+
+    if let Err(err) = message.insert(&state.db).await {
+        tracing::error!("Couldn't queue message for {}: {err:?}", message.recipient);
+        CommandError::ServerError {
+            msg: "Couldn't send message!".to_string()
+        }.into()
     } else {
-        return ArkeCommand::Error(CommandError::InvalidSignature { msg: "Prekey signature is invalid".to_string() }).into();
+        ArkeCommand::Success
     }
-    
-    if let Err(err) = User::from(new_user).insert(&state.db).await {
-        log::error!("Couldn't create new user: {err:?}");
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::FetchMessages(_),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn fetch_messages(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    match Message::take_queued(&state.db, username).await {
+        Ok(messages) => ArkeCommand::Messages(messages),
+        Err(err) => {
+            tracing::error!("Couldn't fetch messages of {username}: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't fetch messages!".to_string()
+            }.into()
+        }
+    }
+}
+
+/// Loads a group on behalf of `username`. Groups `username` isn't a member of
+/// are reported as not found.
+async fn find_group(db: &sqlx::MySqlPool, group_id: &[u8], username: &str) -> Result<Group, ArkeCommand> {
+    match Group::find(db, group_id).await {
+        Ok(Some(group)) if group.member(username).is_some() => Ok(group),
+        Ok(_) => Err(ArkeCommand::Error(CommandError::GroupNotFound)),
+        Err(err) => {
+            tracing::error!("Couldn't look up group: {err:?}");
+            Err(CommandError::ServerError {
+                msg: "Couldn't look up group!".to_string()
+            }.into())
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::CreateGroup(CreateGroup { metadata, members }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn create_group(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    match Group::create(&state.db, username, metadata, &members).await {
+        Ok(group) => ArkeCommand::Group(group),
+        Err(sqlx::Error::RowNotFound) => ArkeCommand::Error(CommandError::UserNotFound),
+        Err(err) => {
+            tracing::error!("Couldn't create group: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't create group!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::GetGroup(GetGroup { group_id }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn get_group(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    match find_group(&state.db, &group_id, username).await {
+        Ok(group) => ArkeCommand::Group(group),
+        Err(response) => response,
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::AddGroupMember(AddGroupMember { group_id, username: member, admin }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn add_group_member(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    let group = match find_group(&state.db, &group_id, username).await {
+        Ok(group) => group,
+        Err(response) => return response,
+    };
+
+    if !group.member(username).is_some_and(|m| m.admin) {
+        return ArkeCommand::Error(CommandError::PermissionDenied);
+    }
+
+    match group.add_member(&state.db, &member, admin).await {
+        Ok(MembershipChange::Done) => ArkeCommand::Success,
+        Ok(MembershipChange::UserNotFound) => ArkeCommand::Error(CommandError::UserNotFound),
+        Ok(MembershipChange::LastAdmin) => ArkeCommand::Error(CommandError::InvalidRequest {
+            msg: "The group's only admin can't be demoted".to_string()
+        }),
+        Err(err) => {
+            tracing::error!("Couldn't add {member} to group: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't add group member!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::RemoveGroupMember(RemoveGroupMember { group_id, username: member }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn remove_group_member(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    let group = match find_group(&state.db, &group_id, username).await {
+        Ok(group) => group,
+        Err(response) => return response,
+    };
+
+    // Members may always leave a group, removing others requires admin rights.
+    if member != username && !group.member(username).is_some_and(|m| m.admin) {
+        return ArkeCommand::Error(CommandError::PermissionDenied);
+    }
+
+    match group.remove_member(&state.db, &member).await {
+        Ok(MembershipChange::Done) => ArkeCommand::Success,
+        Ok(MembershipChange::UserNotFound) => ArkeCommand::Error(CommandError::UserNotFound),
+        Ok(MembershipChange::LastAdmin) => ArkeCommand::Error(CommandError::InvalidRequest {
+            msg: "The group's only admin can't leave while it has other members".to_string()
+        }),
+        Err(err) => {
+            tracing::error!("Couldn't remove {member} from group: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't remove group member!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::SendGroupMessage(GroupMessage { group_id, content }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn send_group_message(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    let group = match Group::find(&state.db, &group_id).await {
+        Ok(Some(group)) => group,
+        Ok(None) => return ArkeCommand::Error(CommandError::GroupNotFound),
+        Err(err) => {
+            tracing::error!("Couldn't look up group: {err:?}");
+            return CommandError::ServerError {
+                msg: "Couldn't send group message!".to_string()
+            }.into();
+        }
+    };
+
+    if group.member(username).is_none() {
+        return ArkeCommand::Error(CommandError::PermissionDenied);
+    }
+
+    match group.fan_out(&state.db, username, &content).await {
+        Ok(queued) => {
+            tracing::debug!("Queued group message from {username} for {queued} members");
+            ArkeCommand::Success
+        }
+        Err(err) => {
+            tracing::error!("Couldn't fan out group message: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't send group message!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::BeginUpload(BeginUpload { size }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn begin_upload(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    match state.attachments.begin_upload(&state.db, username, size).await {
+        Ok(attachment_id) => ArkeCommand::UploadStarted(UploadStarted {
+            attachment_id,
+            max_chunk_size: attachment::MAX_CHUNK_SIZE,
+        }),
+        Err(err) => ArkeCommand::Error(err),
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::UploadChunk(AttachmentChunk { attachment_id, offset, data }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn upload_chunk(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    match state.attachments.upload_chunk(&state.db, username, &attachment_id, offset, &data).await {
+        Ok(()) => ArkeCommand::Success,
+        Err(err) => ArkeCommand::Error(err),
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::FinishUpload(FinishUpload { attachment_id, digest }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn finish_upload(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    match state.attachments.finish_upload(&state.db, username, &attachment_id, &digest).await {
+        Ok(()) => ArkeCommand::Success,
+        Err(err) => ArkeCommand::Error(err),
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::DownloadAttachment(DownloadAttachment { attachment_id, offset, length }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn download_attachment(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    if let Err(err) = connection.username() {
+        return ArkeCommand::Error(err);
+    }
+
+    match state.attachments.download_chunk(&state.db, &attachment_id, offset, length).await {
+        Ok((data, size)) => ArkeCommand::AttachmentData(AttachmentData { attachment_id, offset, size, data }),
+        Err(err) => ArkeCommand::Error(err),
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::SetProfile(SetProfile { version, ciphertext, commitment }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn set_profile(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    let profile = Profile { username: username.to_string(), version, ciphertext, commitment };
+    if let Err(err) = profile.validate() {
+        return ArkeCommand::Error(err);
+    }
+
+    if let Err(err) = profile.save(&state.db).await {
+        tracing::error!("Couldn't save profile of {username}: {err:?}");
         CommandError::ServerError {
-            msg: "Couldn't create new user!".to_string()
+            msg: "Couldn't save profile!".to_string()
         }.into()
     } else {
         ArkeCommand::Success
     }
 }
 
+#[command_handler(state = "state", command(
+    ArkeCommand::GetProfile(GetProfile { username, version }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn get_profile(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    if let Err(err) = connection.username() {
+        return ArkeCommand::Error(err);
+    }
+
+    match Profile::find(&state.db, &username, &version).await {
+        Ok(Some(profile)) => ArkeCommand::Profile(profile),
+        Ok(None) => ArkeCommand::Error(CommandError::ProfileNotFound),
+        Err(err) => {
+            tracing::error!("Couldn't look up profile of {username}: {err:?}");
+            CommandError::ServerError {
+                msg: "Couldn't look up profile!".to_string()
+            }.into()
+        }
+    }
+}
+
+#[command_handler(state = "state", command(
+    ArkeCommand::DiscoverContacts(DiscoverContacts { prefixes }),
+    CommandError::ServerError {
+        msg: "Invalid command".to_string()
+    }.into()
+))]
+async fn discover_contacts(state: State, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand {
+    let username = match connection.username() {
+        Ok(username) => username,
+        Err(err) => return ArkeCommand::Error(err),
+    };
+
+    // Malformed requests are refused before they count against the allowance.
+    if let Err(err) = discovery::validate_prefixes(&prefixes) {
+        return ArkeCommand::Error(err);
+    }
+
+    if let Err(err) = state.discovery.charge(username, prefixes.len() as u32) {
+        tracing::warn!("{username} exceeded the contact discovery limit");
+        return ArkeCommand::Error(err);
+    }
+
+    match discovery::discover(&state.db, &prefixes).await {
+        Ok(usernames) => ArkeCommand::Contacts(usernames),
+        Err(err) => ArkeCommand::Error(err),
+    }
+}
+
 #[command_handler(
     state = "_state",
     command(
@@ -105,59 +1001,230 @@ async fn goodbye(_state: State, command: ArkeCommand) -> ArkeCommand {
     ArkeCommand::Goodbye(None)
 }
 
+/// Resolves once the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Couldn't install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.expect("Couldn't install Ctrl-C handler");
+}
+
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    setup_logger().expect("Couldn't setup logger");
+    // Loaded before parsing the command line so that .env can provide overrides.
+    let dotenv = dotenvy::dotenv();
+    let cli = Cli::parse();
 
-    if let Err(err) = dotenvy::dotenv() {
+    let config = match Config::load(cli.config.as_deref(), cli.overrides) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(command) = cli.command {
+        let Some(url) = config.database.url.as_deref() else {
+            eprintln!("database.url (or DATABASE_URL) must be set");
+            std::process::exit(1);
+        };
+        let result = match sqlx::mysql::MySqlPoolOptions::new().max_connections(1).connect(url).await {
+            Ok(db) => run_command(command, &db).await,
+            Err(err) => Err(format!("Couldn't connect to database: {err}").into()),
+        };
+        if let Err(err) = result {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Err(err) = config.validate() {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+
+    let listeners = config
+        .listen
+        .iter()
+        .map(|listen| {
+            let tls = config.listener_tls(listen).load().map_err(|err| (listen.socket_addr(), err))?;
+            let mut listener = Listener::new(listen.socket_addr(), tls);
+            listener.dual_stack = listen.dual_stack;
+            Ok(listener)
+        })
+        .collect::<Result<Vec<_>, (std::net::SocketAddr, TlsError)>>();
+
+    let listeners = match listeners {
+        Ok(listeners) => listeners,
+        Err((addr, err)) => {
+            eprintln!("Couldn't load TLS settings for {addr}: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    if cli.check_config {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
+    logging::init(config.log_level, config.log_format).expect("Couldn't setup logger");
+
+    if let Err(err) = dotenv {
         warn!("Couldn't load .env file: {err:?}");
     }
 
-    let bind_addr = env::var("BIND_ADDRESS").unwrap_or(String::from("127.0.0.1"));
-    let bind_port = env::var("BIND_PORT").unwrap_or(String::from("8080"));
-
-    let mut reader = std::io::BufReader::new(
-        std::fs::File::open("cert.pem").expect("Couldn't open certificate file"),
-    );
-
-    let certs = rustls_pemfile::certs(&mut reader)
-        .expect("Couldn't read certificates")
-        .into_iter()
-        .map(Certificate)
-        .collect::<Vec<_>>();
-
-    let mut reader = std::io::BufReader::new(
-        std::fs::File::open("privateKey.pem").expect("Couldn't open private key file"),
-    );
-
-    let private_key = rustls_pemfile::pkcs8_private_keys(&mut reader)
-        .expect("Couldn't read private key!")
-        .into_iter()
-        .map(PrivateKey)
-        .collect::<Vec<_>>()
-        .first()
-        .expect("No private key")
-        .clone();
-
-    let pool = sqlx::mysql::MySqlPool::connect(env::var("DATABASE_URL").unwrap().as_ref()).await.unwrap();
-
-    let state = Arc::new(Mutex::new(State::new("localhost", pool)));
-    let server = ArkeServer::builder()
-        .with_bind_addr(std::net::IpAddr::V4(
-            Ipv4Addr::from_str(&bind_addr).expect("Invalid bind address"),
-        ))
-        .with_bind_port(u16::from_str(&bind_port).expect("Invalid bind port"))
-        .with_certs(certs)
-        .with_private_key(private_key)
+    let pool = sqlx::mysql::MySqlPoolOptions::new()
+        .max_connections(config.database.max_connections)
+        .acquire_timeout(config.database.acquire_timeout)
+        .connect(config.database.url.as_deref().unwrap_or_default())
+        .await
+        .expect("Couldn't connect to database");
+
+    let signing_key = SigningKey::load_or_generate(&config.signing_key)
+        .expect("Couldn't load server signing key");
+
+    let key_log = KeyLog::load(&pool).await.expect("Couldn't load key transparency log");
+    let mut state = State::new(config.hostname.clone(), pool.clone(), signing_key);
+    state.key_log = key_log;
+    state.username_cooldown = config.registration.username_cooldown;
+    state.registration = config.registration.pow_difficulty.map(RegistrationGate::new);
+
+    let attachments: AttachmentStore = config.attachments.clone().into();
+    state.attachments = attachments.clone();
+
+    let db = state.db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match attachments.purge_expired(&db).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Purged {n} expired attachments"),
+                Err(err) => tracing::error!("Couldn't purge expired attachments: {err:?}"),
+            }
+        }
+    });
+
+    let state = Arc::new(Mutex::new(state));
+    let limits = &config.limits;
+    let mut builder = ArkeServer::builder()
+        .with_handshake_timeout(limits.handshake_timeout)
+        .with_idle_timeout(limits.idle_timeout)
+        .with_shutdown_timeout(limits.shutdown_timeout)
+        .with_refuse_before_tls(limits.refuse_before_tls)
+        .with_outbound_queue_size(limits.outbound_queue_size)
+        .with_tls_reload_interval(Some(config.tls_reload_interval).filter(|interval| !interval.is_zero()));
+
+    for listener in listeners {
+        builder = builder.with_listener(listener);
+    }
+    if let Some(addr) = config.metrics.address {
+        builder = builder.with_metrics_addr(addr);
+    }
+    if let Some(addr) = config.health.address {
+        builder = builder.with_health_addr(addr).with_health_db(pool.clone());
+    }
+    if let Some(lifetime) = limits.max_connection_lifetime {
+        builder = builder.with_max_connection_lifetime(lifetime);
+    }
+    if let Some(max) = limits.max_connections {
+        builder = builder.with_max_connections(max);
+    }
+    if let Some(max) = limits.max_connections_per_ip {
+        builder = builder.with_max_connections_per_ip(max);
+    }
+
+    let rate_limits = &config.rate_limits;
+    let server = builder
+        .with_rate_limit(ArkeCommand::CreateUser, rate_limits.create_user.into())
+        .with_rate_limit(ArkeCommand::RegistrationChallenge, rate_limits.registration_challenge.into())
+        .with_rate_limit(ArkeCommand::AuthChallenge, rate_limits.auth_challenge.into())
+        .with_rate_limit(ArkeCommand::Authenticate, rate_limits.authenticate.into())
+        .with_rate_limit(ArkeCommand::GetPrekeyBundle, rate_limits.get_prekey_bundle.into())
+        .with_rate_limit(ArkeCommand::SendMessage, rate_limits.send_message.into())
+        .with_rate_limit(ArkeCommand::DiscoverContacts, rate_limits.discover_contacts.into())
+        .with_rate_limit(ArkeCommand::BeginUpload, rate_limits.begin_upload.into())
         .handlers(arke::routes! {
             Arc::clone(&state),
             ArkeCommand::Hello => hello,
             ArkeCommand::CreateUser => create_user,
+            ArkeCommand::RegistrationChallenge => registration_challenge,
+            ArkeCommand::AuthChallenge => auth_challenge,
+            ArkeCommand::Authenticate => authenticate,
+            ArkeCommand::DeleteAccount => delete_account,
+            ArkeCommand::GetIdentityKey => get_identity_key,
+            ArkeCommand::GetPrekeyBundle => get_prekey_bundle,
+            ArkeCommand::GetServerKey => get_server_key,
+            ArkeCommand::GetTreeHead => get_tree_head,
+            ArkeCommand::GetConsistencyProof => get_consistency_proof,
+            ArkeCommand::SetUnidentifiedAccess => set_unidentified_access,
+            ArkeCommand::GetSenderCertificate => get_sender_certificate,
+            ArkeCommand::SendMessage => send_message,
+            ArkeCommand::FetchMessages => fetch_messages,
+            ArkeCommand::CreateGroup => create_group,
+            ArkeCommand::GetGroup => get_group,
+            ArkeCommand::AddGroupMember => add_group_member,
+            ArkeCommand::RemoveGroupMember => remove_group_member,
+            ArkeCommand::SendGroupMessage => send_group_message,
+            ArkeCommand::BeginUpload => begin_upload,
+            ArkeCommand::UploadChunk => upload_chunk,
+            ArkeCommand::FinishUpload => finish_upload,
+            ArkeCommand::DownloadAttachment => download_attachment,
+            ArkeCommand::SetProfile => set_profile,
+            ArkeCommand::GetProfile => get_profile,
+            ArkeCommand::DiscoverContacts => discover_contacts,
             ArkeCommand::Goodbye => goodbye
         })
         .build()
         .await
         .expect("Couldn't build server!");
 
-    server.start().await
+    if let Some(metrics) = server.metrics() {
+        let db = state.lock().await.db.clone();
+        let sample_interval = config.metrics.sample_interval;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(sample_interval);
+            loop {
+                interval.tick().await;
+                if let Err(err) = metrics.sample_db(&db).await {
+                    tracing::error!("Couldn't sample database metrics: {err:?}");
+                }
+            }
+        });
+    }
+
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("Received shutdown signal");
+        shutdown.shutdown();
+    });
+
+    #[cfg(unix)]
+    {
+        let reloader = server.tls_reloader();
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Couldn't install SIGHUP handler");
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                tracing::info!("Received SIGHUP, reloading TLS certificates");
+                reloader.reload();
+            }
+        });
+    }
+
+    server.start().await?;
+
+    state.lock().await.db.close().await;
+    tracing::info!("Closed database connections");
+    Ok(())
 }
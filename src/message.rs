@@ -0,0 +1,81 @@
+use macros::Entity;
+use serde::{Deserialize, Serialize};
+use sqlx::{mysql::MySqlPool, FromRow, Type};
+use std::time::Duration;
+
+#[derive(Type, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum MessageKind {
+    Direct = 0,
+    System = 1,
+    /// Delivered without the server knowing the sender, see [`crate::sealed_sender`].
+    Sealed = 2,
+    /// Fanned out to every member of a [`crate::group::Group`].
+    Group = 3,
+}
+
+/// Messages generated by the server itself. These are queued with
+/// [`MessageKind::System`] and a JSON encoded body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum SystemMessage {
+    AccountDeleted { username: String },
+}
+
+impl SystemMessage {
+    pub fn content(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Couldn't serialize system message")
+    }
+}
+
+/// A message waiting in a recipient's queue.
+#[derive(Entity, FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub recipient: String,
+    pub sender: Option<String>,
+    pub kind: MessageKind,
+    pub content: Vec<u8>,
+}
+
+impl Message {
+    /// Removes and returns every message queued for `recipient`, oldest first.
+    pub async fn take_queued(db: &MySqlPool, recipient: &str) -> Result<Vec<Message>, sqlx::Error> {
+        let mut tx = db.begin().await?;
+
+        let messages = sqlx::query_as(
+            "SELECT recipient, sender, kind, content FROM message \
+             WHERE recipient = ? ORDER BY message_id FOR UPDATE",
+        )
+        .bind(recipient)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM message WHERE recipient = ?")
+            .bind(recipient)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(messages)
+    }
+
+    pub async fn count_queued(db: &MySqlPool, recipient: &str) -> Result<u64, sqlx::Error> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM message WHERE recipient = ?")
+            .bind(recipient)
+            .fetch_one(db)
+            .await?;
+        Ok(count as u64)
+    }
+
+    /// Deletes queued messages that have waited longer than `age` and returns
+    /// how many were removed.
+    pub async fn purge_older_than(db: &MySqlPool, age: Duration) -> Result<u64, sqlx::Error> {
+        let purged = sqlx::query(
+            "DELETE FROM message WHERE created_at < DATE_SUB(NOW(), INTERVAL ? SECOND)",
+        )
+        .bind(age.as_secs())
+        .execute(db)
+        .await?;
+        Ok(purged.rows_affected())
+    }
+}
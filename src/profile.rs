@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{mysql::MySqlPool, FromRow};
+
+use crate::server::command::CommandError;
+
+pub const MAX_VERSION_LENGTH: usize = 64;
+pub const MAX_COMMITMENT_LENGTH: usize = 64;
+pub const MAX_PROFILE_SIZE: usize = 16 * 1024;
+
+/// A user's profile encrypted with their profile key. The server only ever sees
+/// the ciphertext and a commitment to the profile key, which contacts holding
+/// the key can use to check that they were served the right profile. Each
+/// profile key gets its own `version` so that contacts that haven't received a
+/// rotated key can still read the profile they know about.
+#[derive(Debug, Default, Clone, FromRow, Serialize, Deserialize)]
+pub struct Profile {
+    pub username: String,
+    pub version: String,
+    pub ciphertext: Vec<u8>,
+    pub commitment: Vec<u8>,
+}
+
+impl Profile {
+    pub fn validate(&self) -> Result<(), CommandError> {
+        let invalid = |msg: &str| {
+            Err(CommandError::InvalidRequest {
+                msg: msg.to_string(),
+            })
+        };
+
+        if self.version.is_empty() || self.version.len() > MAX_VERSION_LENGTH {
+            invalid("Invalid profile version")
+        } else if self.commitment.is_empty() || self.commitment.len() > MAX_COMMITMENT_LENGTH {
+            invalid("Invalid profile key commitment")
+        } else if self.ciphertext.len() > MAX_PROFILE_SIZE {
+            invalid("Profile is too large")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stores this profile, replacing an earlier one with the same version.
+    pub async fn save(&self, db: &MySqlPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO profile(username, version, ciphertext, commitment) VALUES (?, ?, ?, ?) \
+             ON DUPLICATE KEY UPDATE ciphertext = VALUES(ciphertext), commitment = VALUES(commitment)",
+        )
+        .bind(&self.username)
+        .bind(&self.version)
+        .bind(&self.ciphertext)
+        .bind(&self.commitment)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn find(db: &MySqlPool, username: &str, version: &str) -> Result<Option<Profile>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT username, version, ciphertext, commitment FROM profile WHERE username = ? AND version = ?",
+        )
+        .bind(username)
+        .bind(version)
+        .fetch_optional(db)
+        .await
+    }
+}
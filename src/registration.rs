@@ -0,0 +1,290 @@
+//! Optional hashcash-style proof of work for account registration. Clients
+//! request a [`RegistrationChallenge`] and must find a `solution` such that
+//! `SHA-256(nonce || username || solution)` starts with at least `difficulty`
+//! zero bits. The difficulty rises while many accounts are being registered.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{crypto, server::command::CommandError};
+
+pub const NONCE_LENGTH: usize = 16;
+pub const CHALLENGE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// Upper bound on the number of outstanding challenges.
+const MAX_PENDING_CHALLENGES: usize = 100_000;
+
+/// Upper bound on the number of outstanding challenges issued to a single
+/// client address, or IPv6 /64 network.
+pub const MAX_PENDING_CHALLENGES_PER_PEER: usize = 16;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RegistrationChallenge {
+    pub nonce: Vec<u8>,
+    pub difficulty: u8,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ProofOfWork {
+    pub nonce: Vec<u8>,
+    pub solution: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct RegistrationGate {
+    /// Difficulty while the server isn't under load.
+    pub difficulty: u8,
+    pub max_difficulty: u8,
+    /// Every this many registrations within `load_window` add one bit of difficulty.
+    pub registrations_per_step: u32,
+    pub load_window: Duration,
+    challenges: HashMap<Vec<u8>, PendingChallenge>,
+    /// Nonces in the order they were issued, so that expired challenges can be
+    /// dropped from the front. Nonces of challenges that were already used are
+    /// skipped.
+    issued: VecDeque<(Instant, Vec<u8>)>,
+    pending_per_peer: HashMap<IpAddr, usize>,
+    registrations: VecDeque<Instant>,
+}
+
+#[derive(Debug)]
+struct PendingChallenge {
+    difficulty: u8,
+    peer: IpAddr,
+}
+
+impl RegistrationGate {
+    pub fn new(difficulty: u8) -> Self {
+        Self {
+            difficulty,
+            max_difficulty: difficulty.saturating_add(8),
+            registrations_per_step: 50,
+            load_window: Duration::from_secs(60),
+            challenges: HashMap::new(),
+            issued: VecDeque::new(),
+            pending_per_peer: HashMap::new(),
+            registrations: VecDeque::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((issued, _)) = self.issued.front() {
+            if now.duration_since(*issued) < CHALLENGE_LIFETIME {
+                break;
+            }
+            let (_, nonce) = self.issued.pop_front().unwrap();
+            self.take(&nonce);
+        }
+
+        while self
+            .registrations
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= self.load_window)
+        {
+            self.registrations.pop_front();
+        }
+    }
+
+    /// The difficulty new challenges are issued with given the current load.
+    pub fn current_difficulty(&self) -> u8 {
+        let steps = self.registrations.len() as u32 / self.registrations_per_step.max(1);
+        self.difficulty
+            .saturating_add(steps.min(u8::MAX as u32) as u8)
+            .min(self.max_difficulty)
+    }
+
+    /// Issues a challenge to a client connecting from `peer`. Fails with
+    /// [`CommandError::RateLimited`] while the client has too many outstanding
+    /// challenges.
+    pub fn issue(&mut self, peer: IpAddr) -> Result<RegistrationChallenge, CommandError> {
+        let now = Instant::now();
+        self.expire(now);
+
+        let peer = crate::peer_key(peer);
+        if self.pending_per_peer.get(&peer).copied().unwrap_or(0) >= MAX_PENDING_CHALLENGES_PER_PEER {
+            return Err(CommandError::RateLimited {
+                retry_after: CHALLENGE_LIFETIME.as_secs(),
+            });
+        }
+        if self.challenges.len() >= MAX_PENDING_CHALLENGES {
+            return Err(CommandError::ServerBusy);
+        }
+
+        let challenge = RegistrationChallenge {
+            nonce: crypto::random_bytes(NONCE_LENGTH),
+            difficulty: self.current_difficulty(),
+        };
+        self.challenges.insert(
+            challenge.nonce.clone(),
+            PendingChallenge {
+                difficulty: challenge.difficulty,
+                peer,
+            },
+        );
+        self.issued.push_back((now, challenge.nonce.clone()));
+        *self.pending_per_peer.entry(peer).or_default() += 1;
+
+        // Nonces of used challenges stay queued until they would have expired.
+        // Drop them once they make up most of the queue.
+        if self.issued.len() > 2 * self.challenges.len() + 1024 {
+            let challenges = &self.challenges;
+            self.issued.retain(|(_, nonce)| challenges.contains_key(nonce));
+        }
+
+        Ok(challenge)
+    }
+
+    /// Checks and consumes the challenge solved by `proof`.
+    pub fn verify(
+        &mut self,
+        username: &str,
+        proof: Option<&ProofOfWork>,
+    ) -> Result<(), CommandError> {
+        let now = Instant::now();
+        self.expire(now);
+
+        let proof = proof.ok_or(CommandError::InvalidProofOfWork)?;
+        let difficulty = self.take(&proof.nonce).ok_or(CommandError::InvalidProofOfWork)?;
+
+        if solves(&proof.nonce, username, &proof.solution, difficulty) {
+            Ok(())
+        } else {
+            Err(CommandError::InvalidProofOfWork)
+        }
+    }
+
+    /// Removes a pending challenge, returning its difficulty.
+    fn take(&mut self, nonce: &[u8]) -> Option<u8> {
+        let challenge = self.challenges.remove(nonce)?;
+        if let Some(pending) = self.pending_per_peer.get_mut(&challenge.peer) {
+            *pending -= 1;
+            if *pending == 0 {
+                self.pending_per_peer.remove(&challenge.peer);
+            }
+        }
+        Some(challenge.difficulty)
+    }
+
+    pub fn record_registration(&mut self) {
+        self.registrations.push_back(Instant::now());
+    }
+}
+
+/// Whether `solution` solves the challenge `nonce` for `username` at `difficulty`.
+fn solves(nonce: &[u8], username: &str, solution: &[u8], difficulty: u8) -> bool {
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(username.as_bytes());
+    data.extend_from_slice(solution);
+    leading_zero_bits(&openssl::sha::sha256(&data)) >= difficulty as u32
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    fn solve(challenge: &RegistrationChallenge, username: &str) -> ProofOfWork {
+        let solution = (0u64..)
+            .map(|i| i.to_be_bytes().to_vec())
+            .find(|solution| solves(&challenge.nonce, username, solution, challenge.difficulty))
+            .unwrap();
+        ProofOfWork {
+            nonce: challenge.nonce.clone(),
+            solution,
+        }
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x01, 0xff]), 7);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x20]), 18);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn solved_challenge_is_accepted_once() {
+        let mut gate = RegistrationGate::new(8);
+        let challenge = gate.issue(PEER).unwrap();
+        assert_eq!(challenge.difficulty, 8);
+
+        let proof = solve(&challenge, "alice");
+        assert!(gate.verify("alice", Some(&proof)).is_ok());
+        assert!(gate.verify("alice", Some(&proof)).is_err());
+    }
+
+    #[test]
+    fn proof_is_bound_to_username_and_nonce() {
+        let mut gate = RegistrationGate::new(8);
+        let challenge = gate.issue(PEER).unwrap();
+        let solution = (0u64..)
+            .map(|i| i.to_be_bytes().to_vec())
+            .find(|s| solves(&challenge.nonce, "alice", s, 8) && !solves(&challenge.nonce, "bob", s, 8))
+            .unwrap();
+
+        let unknown = ProofOfWork {
+            nonce: crypto::random_bytes(NONCE_LENGTH),
+            solution: solution.clone(),
+        };
+        assert!(gate.verify("alice", Some(&unknown)).is_err());
+        assert!(gate.verify("alice", None).is_err());
+
+        let proof = ProofOfWork {
+            nonce: challenge.nonce,
+            solution,
+        };
+        assert!(gate.verify("bob", Some(&proof)).is_err());
+    }
+
+    #[test]
+    fn pending_challenges_are_capped_per_peer() {
+        let mut gate = RegistrationGate::new(0);
+        for _ in 0..MAX_PENDING_CHALLENGES_PER_PEER {
+            assert!(gate.issue(PEER).is_ok());
+        }
+        assert!(matches!(gate.issue(PEER), Err(CommandError::RateLimited { .. })));
+        assert!(gate.issue(IpAddr::from([192, 0, 2, 2])).is_ok());
+
+        let network: IpAddr = "2001:db8::1".parse().unwrap();
+        for _ in 0..MAX_PENDING_CHALLENGES_PER_PEER {
+            assert!(gate.issue(network).is_ok());
+        }
+        assert!(gate.issue("2001:db8::2".parse().unwrap()).is_err());
+        assert!(gate.issue("2001:db8:0:1::1".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn used_and_expired_challenges_free_their_slot() {
+        let mut gate = RegistrationGate::new(0);
+        let challenges = (0..MAX_PENDING_CHALLENGES_PER_PEER)
+            .map(|_| gate.issue(PEER).unwrap())
+            .collect::<Vec<_>>();
+
+        let proof = solve(&challenges[0], "alice");
+        assert!(gate.verify("alice", Some(&proof)).is_ok());
+        assert!(gate.issue(PEER).is_ok());
+        assert!(gate.issue(PEER).is_err());
+
+        gate.expire(Instant::now() + CHALLENGE_LIFETIME);
+        assert!(gate.challenges.is_empty());
+        assert!(gate.issued.is_empty());
+        assert!(gate.pending_per_peer.is_empty());
+        assert!(gate.issue(PEER).is_ok());
+    }
+}
@@ -0,0 +1,73 @@
+//! Sealed sender delivery. A sender who knows the recipient's unidentified
+//! access key, which clients derive from the recipient's profile key, can queue
+//! a message without authenticating. The sender's identity is instead carried
+//! inside the encrypted envelope as a short-lived [`SenderCertificate`] signed by
+//! the server.
+
+use serde::{Deserialize, Serialize};
+use sqlx::mysql::MySqlPool;
+use std::time::Duration;
+
+use crate::crypto::{PublicKey, SigningKey};
+
+pub const SENDER_CERTIFICATE_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Upper bound on the length of an unidentified access key.
+pub const MAX_ACCESS_KEY_LENGTH: usize = 64;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SenderCertificate {
+    pub username: String,
+    pub identity_key: PublicKey,
+    pub expires_at: u64,
+    pub signature: Vec<u8>,
+}
+
+impl SenderCertificate {
+    pub fn new(username: String, identity_key: PublicKey, signing_key: &SigningKey) -> Self {
+        let mut certificate = Self {
+            username,
+            identity_key,
+            expires_at: crate::unix_timestamp() + SENDER_CERTIFICATE_LIFETIME.as_secs(),
+            signature: vec![],
+        };
+        certificate.signature = signing_key.sign(&certificate.signed_data());
+        certificate
+    }
+
+    /// The bytes covered by `signature`: the username, a NUL byte, the identity
+    /// key and the big endian expiry timestamp.
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(self.username.as_bytes());
+        data.push(0);
+        data.extend_from_slice(self.identity_key.as_ref());
+        data.extend_from_slice(&self.expires_at.to_be_bytes());
+        data
+    }
+}
+
+pub async fn set_access_key(db: &MySqlPool, username: &str, access_key: &[u8]) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE user SET unidentified_access_key = ? WHERE username = ?")
+        .bind(access_key)
+        .bind(username)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Checks `access_key` against the one registered by `recipient`. Fails if the
+/// recipient doesn't exist or hasn't enabled sealed sender delivery.
+pub async fn verify_access_key(db: &MySqlPool, recipient: &str, access_key: &[u8]) -> Result<bool, sqlx::Error> {
+    let stored: Option<(Option<Vec<u8>>,)> =
+        sqlx::query_as("SELECT unidentified_access_key FROM user WHERE username = ?")
+            .bind(recipient)
+            .fetch_optional(db)
+            .await?;
+
+    Ok(match stored {
+        Some((Some(stored),)) => {
+            stored.len() == access_key.len() && openssl::memcmp::eq(&stored, access_key)
+        }
+        _ => false,
+    })
+}
@@ -1,4 +1,14 @@
-use crate::user::NewUser;
+use super::connection::Connection;
+use crate::{
+    group::Group,
+    identity::SignedIdentityKey,
+    message::Message,
+    profile::Profile,
+    registration::RegistrationChallenge,
+    sealed_sender::SenderCertificate,
+    transparency::{ConsistencyProof, SignedTreeHead},
+    user::{NewUser, PrekeyBundle},
+};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
@@ -34,6 +44,216 @@ pub enum ArkeCommand {
     Goodbye(Option<CommandError>) = 3,
     Error(CommandError) = 4,
     InsertPrekeys(Vec<crate::crypto::PublicKey>) = 5,
+    AuthChallenge(AuthChallenge) = 6,
+    Authenticate(Authenticate) = 7,
+    DeleteAccount(DeleteAccount) = 8,
+    GetIdentityKey(GetIdentityKey) = 9,
+    IdentityKey(SignedIdentityKey) = 10,
+    GetPrekeyBundle(GetPrekeyBundle) = 11,
+    PrekeyBundle(PrekeyBundle) = 12,
+    GetTreeHead(GetTreeHead) = 13,
+    TreeHead(SignedTreeHead) = 14,
+    GetConsistencyProof(GetConsistencyProof) = 15,
+    ConsistencyProof(ConsistencyProof) = 16,
+    SetUnidentifiedAccess(SetUnidentifiedAccess) = 17,
+    GetSenderCertificate(GetSenderCertificate) = 18,
+    SenderCertificate(SenderCertificate) = 19,
+    SendMessage(OutgoingMessage) = 20,
+    FetchMessages(FetchMessages) = 21,
+    Messages(Vec<Message>) = 22,
+    CreateGroup(CreateGroup) = 23,
+    GetGroup(GetGroup) = 24,
+    Group(Group) = 25,
+    AddGroupMember(AddGroupMember) = 26,
+    RemoveGroupMember(RemoveGroupMember) = 27,
+    SendGroupMessage(GroupMessage) = 28,
+    BeginUpload(BeginUpload) = 29,
+    UploadStarted(UploadStarted) = 30,
+    UploadChunk(AttachmentChunk) = 31,
+    FinishUpload(FinishUpload) = 32,
+    DownloadAttachment(DownloadAttachment) = 33,
+    AttachmentData(AttachmentData) = 34,
+    SetProfile(SetProfile) = 35,
+    GetProfile(GetProfile) = 36,
+    Profile(Profile) = 37,
+    DiscoverContacts(DiscoverContacts) = 38,
+    Contacts(Vec<String>) = 39,
+    RegistrationChallenge(RegistrationChallenge) = 40,
+    /// Keepalive answered by the server with a [`ArkeCommand::Pong`] carrying the same payload.
+    Ping(u64) = 41,
+    Pong(u64) = 42,
+    /// Answered with [`ArkeCommand::ServerKey`], the key that signs identity
+    /// keys, tree heads and sender certificates.
+    GetServerKey(GetServerKey) = 43,
+    ServerKey(crate::crypto::PublicKey) = 44,
+}
+
+/// Sent by the client to request a challenge and answered by the server with a
+/// fresh nonce; the client answers with [`Authenticate`] carrying its identity
+/// key's signature over [`AuthChallenge::signed_data`].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AuthChallenge {
+    pub nonce: Vec<u8>,
+}
+
+impl AuthChallenge {
+    /// Prefixed to the nonce so a challenge signature can't be mistaken for a
+    /// signature over any other message made with the identity key.
+    pub const CONTEXT: &'static [u8] = b"arke-auth-v1";
+
+    /// The bytes the client signs: [`Self::CONTEXT`] followed by the nonce.
+    pub fn signed_data(&self) -> Vec<u8> {
+        [Self::CONTEXT, &self.nonce].concat()
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Authenticate {
+    pub username: String,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DeleteAccount {
+    /// Usernames that should receive a system message about the deletion.
+    pub notify: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GetIdentityKey {
+    pub username: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GetPrekeyBundle {
+    pub username: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GetTreeHead {}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GetServerKey {}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GetConsistencyProof {
+    pub first: u64,
+    pub second: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SetUnidentifiedAccess {
+    pub access_key: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GetSenderCertificate {}
+
+/// A message to queue for `recipient`. Without an `access_key` the connection
+/// must be authenticated and the message is queued with its sender. With the
+/// recipient's unidentified access key the message is delivered sealed, and
+/// `content` is expected to carry the sender's [`SenderCertificate`].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OutgoingMessage {
+    pub recipient: String,
+    pub content: Vec<u8>,
+    pub access_key: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FetchMessages {}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CreateGroup {
+    pub metadata: Vec<u8>,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GetGroup {
+    pub group_id: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AddGroupMember {
+    pub group_id: Vec<u8>,
+    pub username: String,
+    pub admin: bool,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RemoveGroupMember {
+    pub group_id: Vec<u8>,
+    pub username: String,
+}
+
+/// A single ciphertext that is queued for every other member of the group.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GroupMessage {
+    pub group_id: Vec<u8>,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct BeginUpload {
+    pub size: u64,
+}
+
+/// Returned by [`ArkeCommand::BeginUpload`]. Once the upload is finished the id
+/// identifies the attachment for downloads.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UploadStarted {
+    pub attachment_id: Vec<u8>,
+    pub max_chunk_size: usize,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AttachmentChunk {
+    pub attachment_id: Vec<u8>,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FinishUpload {
+    pub attachment_id: Vec<u8>,
+    /// SHA-256 digest of the complete ciphertext.
+    pub digest: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DownloadAttachment {
+    pub attachment_id: Vec<u8>,
+    pub offset: u64,
+    pub length: usize,
+}
+
+/// A chunk of a downloaded attachment. `size` is the total size of the attachment.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AttachmentData {
+    pub attachment_id: Vec<u8>,
+    pub offset: u64,
+    pub size: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SetProfile {
+    pub version: String,
+    pub ciphertext: Vec<u8>,
+    pub commitment: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GetProfile {
+    pub username: String,
+    pub version: String,
+}
+
+/// Truncated SHA-256 hashes of normalized usernames, see [`crate::discovery`].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DiscoverContacts {
+    pub prefixes: Vec<Vec<u8>>,
 }
 
 impl ArkeCommand {
@@ -48,15 +268,70 @@ pub enum CommandError {
     ServerError { msg: String },
     InvalidSignature { msg: String },
     InvalidKey,
+    InvalidUsername { msg: String },
+    UsernameTaken,
+    NotAuthenticated,
+    AuthenticationFailed,
+    UserNotFound,
+    InvalidRequest { msg: String },
+    GroupNotFound,
+    PermissionDenied,
+    AttachmentNotFound,
+    QuotaExceeded,
+    ProfileNotFound,
+    /// The command may be retried after `retry_after` seconds.
+    RateLimited { retry_after: u64 },
+    InvalidProofOfWork,
+    ServerBusy,
+    Timeout,
+    ShuttingDown,
 }
 
-impl Into<ArkeCommand> for CommandError {
-    fn into(self) -> ArkeCommand {
-        ArkeCommand::Goodbye(Some(self))
+impl CommandError {
+    /// Name of the variant as sent in the `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ServerError { .. } => "ServerError",
+            Self::InvalidSignature { .. } => "InvalidSignature",
+            Self::InvalidKey => "InvalidKey",
+            Self::InvalidUsername { .. } => "InvalidUsername",
+            Self::UsernameTaken => "UsernameTaken",
+            Self::NotAuthenticated => "NotAuthenticated",
+            Self::AuthenticationFailed => "AuthenticationFailed",
+            Self::UserNotFound => "UserNotFound",
+            Self::InvalidRequest { .. } => "InvalidRequest",
+            Self::GroupNotFound => "GroupNotFound",
+            Self::PermissionDenied => "PermissionDenied",
+            Self::AttachmentNotFound => "AttachmentNotFound",
+            Self::QuotaExceeded => "QuotaExceeded",
+            Self::ProfileNotFound => "ProfileNotFound",
+            Self::RateLimited { .. } => "RateLimited",
+            Self::InvalidProofOfWork => "InvalidProofOfWork",
+            Self::ServerBusy => "ServerBusy",
+            Self::Timeout => "Timeout",
+            Self::ShuttingDown => "ShuttingDown",
+        }
+    }
+}
+
+impl From<CommandError> for ArkeCommand {
+    fn from(value: CommandError) -> ArkeCommand {
+        ArkeCommand::Goodbye(Some(value))
     }
 }
 
 #[async_trait]
 pub trait CommandHandler: Send {
-    async fn handle(&mut self, command: ArkeCommand) -> ArkeCommand;
+    async fn handle(&mut self, connection: &mut Connection, command: ArkeCommand) -> ArkeCommand;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_challenge_signs_nonce_under_context() {
+        let challenge = AuthChallenge { nonce: vec![1, 2, 3] };
+        assert_eq!(challenge.signed_data(), b"arke-auth-v1\x01\x02\x03");
+    }
 }
@@ -0,0 +1,169 @@
+use super::command::CommandError;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+/// Per-connection state that is handed to every command handler alongside the
+/// shared server [`State`](super::state::State).
+#[derive(Debug)]
+pub struct Connection {
+    pub peer_addr: SocketAddr,
+    pub handshake: bool,
+    /// Protocol version the client announced in a successful handshake.
+    pub version: Option<(u8, u8, u8)>,
+    pub challenge: Option<Vec<u8>>,
+    pub user: Option<String>,
+    /// Subject of the verified certificate the client presented, if the
+    /// listener has client authentication (mTLS) enabled.
+    pub client_subject: Option<String>,
+}
+
+impl Connection {
+    pub fn new(peer_addr: SocketAddr) -> Self {
+        Self {
+            peer_addr,
+            handshake: false,
+            version: None,
+            challenge: None,
+            user: None,
+            client_subject: None,
+        }
+    }
+
+    /// Returns the username this connection has authenticated as.
+    pub fn username(&self) -> Result<&str, CommandError> {
+        self.user.as_deref().ok_or(CommandError::NotAuthenticated)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// Refuse excess connections before the TLS handshake rather than after it.
+    /// The refusal is sent in plain text, but excess connections can't make the
+    /// server perform handshakes. Otherwise there is no limit on handshakes
+    /// with connections that are refused afterwards.
+    pub refuse_before_tls: bool,
+    /// Number of outgoing commands that may be waiting to be written to a
+    /// connection before the server stops reading from it.
+    pub outbound_queue_size: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            refuse_before_tls: true,
+            outbound_queue_size: 32,
+        }
+    }
+}
+
+/// Counts open connections, globally and per peer address or IPv6 /64
+/// network, see [`crate::peer_key`].
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    /// Kept at the number of open connections.
+    gauge: Option<prometheus::IntGauge>,
+}
+
+impl ConnectionTracker {
+    pub fn with_gauge(gauge: prometheus::IntGauge) -> Self {
+        Self {
+            gauge: Some(gauge),
+            ..Default::default()
+        }
+    }
+
+    fn update_gauge(&self) {
+        if let Some(gauge) = &self.gauge {
+            gauge.set(self.total as i64);
+        }
+    }
+
+    /// Registers a connection from `ip` unless that would exceed `limits`. The
+    /// connection counts as open until the returned slot is dropped.
+    pub fn acquire(
+        tracker: &Arc<Mutex<ConnectionTracker>>,
+        ip: IpAddr,
+        limits: &ConnectionLimits,
+    ) -> Option<ConnectionSlot> {
+        let ip = crate::peer_key(ip);
+        let mut guard = tracker.lock().expect("Connection tracker lock poisoned");
+        let from_ip = guard.per_ip.get(&ip).copied().unwrap_or(0);
+
+        if limits.max_connections.is_some_and(|max| guard.total >= max)
+            || limits
+                .max_connections_per_ip
+                .is_some_and(|max| from_ip >= max)
+        {
+            return None;
+        }
+
+        guard.total += 1;
+        guard.per_ip.insert(ip, from_ip + 1);
+        guard.update_gauge();
+
+        Some(ConnectionSlot {
+            tracker: Arc::clone(tracker),
+            ip,
+        })
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    tracker: Arc<Mutex<ConnectionTracker>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut tracker = self
+            .tracker
+            .lock()
+            .expect("Connection tracker lock poisoned");
+        tracker.total -= 1;
+        if let Some(count) = tracker.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                tracker.per_ip.remove(&self.ip);
+            }
+        }
+        tracker.update_gauge();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_peers_share_their_64() {
+        let tracker = Arc::new(Mutex::new(ConnectionTracker::default()));
+        let limits = ConnectionLimits {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        };
+        let addr = |network: u16, last: u16| IpAddr::from([0x2001, 0xdb8, 0, network, 0, 0, 0, last]);
+
+        let slot = ConnectionTracker::acquire(&tracker, addr(1, 1), &limits).unwrap();
+        assert!(ConnectionTracker::acquire(&tracker, addr(1, 2), &limits).is_none());
+        let other = ConnectionTracker::acquire(&tracker, addr(2, 1), &limits).unwrap();
+
+        drop(slot);
+        assert!(ConnectionTracker::acquire(&tracker, addr(1, 2), &limits).is_some());
+        drop(other);
+        assert_eq!(tracker.lock().unwrap().per_ip.len(), 0);
+    }
+}
@@ -0,0 +1,169 @@
+//! Liveness and readiness probes for orchestrators, served over plain HTTP at
+//! `/healthz` and `/readyz`, see
+//! [`ArkeServerBuilder::with_health_addr`](super::ArkeServerBuilder::with_health_addr).
+
+use super::http::{Endpoint, Response};
+use async_trait::async_trait;
+use sqlx::mysql::MySqlPool;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// Migrations this build of the server expects to have been applied.
+pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
+
+/// Time the database has to answer a readiness check.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Answers `/healthz` whenever the process is able to respond at all.
+#[derive(Debug, Default)]
+pub struct Liveness;
+
+#[async_trait]
+impl Endpoint for Liveness {
+    async fn respond(&self) -> Response {
+        Response::text(200, "ok\n")
+    }
+}
+
+/// Answers `/readyz` once the server accepts connections, the database is
+/// reachable and all migrations have been applied as this build knows them.
+#[derive(Debug, Default)]
+pub struct Readiness {
+    /// Set while the server is accepting connections on all of its listeners.
+    pub(crate) accepting: AtomicBool,
+    pub(crate) db: Option<MySqlPool>,
+}
+
+impl Readiness {
+    pub fn new(db: Option<MySqlPool>) -> Self {
+        Self {
+            accepting: AtomicBool::new(false),
+            db,
+        }
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    /// Returns the reasons the server isn't ready, if any.
+    pub async fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !self.is_accepting() {
+            problems.push("server isn't accepting connections".to_string());
+        }
+
+        if let Some(db) = &self.db {
+            match tokio::time::timeout(DB_CHECK_TIMEOUT, migration_status(db)).await {
+                Ok(Ok(status)) => {
+                    if !status.pending.is_empty() {
+                        problems.push(format!("migrations not applied: {}", join(&status.pending)));
+                    }
+                    if !status.modified.is_empty() {
+                        problems.push(format!(
+                            "migrations changed since they were applied: {}",
+                            join(&status.modified)
+                        ));
+                    }
+                }
+                Ok(Err(err)) => problems.push(format!("database unavailable: {err}")),
+                Err(_) => problems.push("database didn't respond in time".to_string()),
+            }
+        }
+
+        problems
+    }
+}
+
+fn join(versions: &[i64]) -> String {
+    versions
+        .iter()
+        .map(|version| version.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// How the migrations known to this build compare to those the database has
+/// applied.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrationStatus {
+    /// Versions that haven't been applied.
+    pub pending: Vec<i64>,
+    /// Versions that were applied with a different checksum, i.e. whose
+    /// migration has been edited since.
+    pub modified: Vec<i64>,
+}
+
+pub async fn migration_status(db: &MySqlPool) -> Result<MigrationStatus, sqlx::Error> {
+    let applied: Vec<(i64, Vec<u8>)> =
+        match sqlx::query_as("SELECT version, checksum FROM _sqlx_migrations WHERE success = TRUE")
+            .fetch_all(db)
+            .await
+        {
+            Ok(applied) => applied,
+            // No migrations have been run yet.
+            Err(sqlx::Error::Database(err)) if err.code().as_deref() == Some("42S02") => vec![],
+            Err(err) => return Err(err),
+        };
+
+    Ok(compare_migrations(&applied.into_iter().collect()))
+}
+
+/// Compares [`MIGRATOR`] against the checksums of applied migrations by version.
+fn compare_migrations(applied: &HashMap<i64, Vec<u8>>) -> MigrationStatus {
+    let mut status = MigrationStatus::default();
+    for migration in MIGRATOR.iter().filter(|m| !m.migration_type.is_down_migration()) {
+        match applied.get(&migration.version) {
+            None => status.pending.push(migration.version),
+            Some(checksum) if *checksum != *migration.checksum => status.modified.push(migration.version),
+            Some(_) => {}
+        }
+    }
+    status
+}
+
+#[async_trait]
+impl Endpoint for Readiness {
+    async fn respond(&self) -> Response {
+        let problems = self.check().await;
+        if problems.is_empty() {
+            Response::text(200, "ready\n")
+        } else {
+            Response::text(503, problems.join("\n") + "\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied() -> HashMap<i64, Vec<u8>> {
+        MIGRATOR
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration())
+            .map(|m| (m.version, m.checksum.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn all_migrations_applied() {
+        assert_eq!(compare_migrations(&applied()), MigrationStatus::default());
+    }
+
+    #[test]
+    fn missing_and_edited_migrations() {
+        let mut applied = applied();
+        let mut versions = applied.keys().copied().collect::<Vec<_>>();
+        versions.sort();
+        applied.remove(&versions[versions.len() - 1]);
+        applied.insert(versions[0], vec![0; 48]);
+
+        let status = compare_migrations(&applied);
+        assert_eq!(status.pending, [versions[versions.len() - 1]]);
+        assert_eq!(status.modified, [versions[0]]);
+    }
+}
@@ -0,0 +1,146 @@
+//! A minimal plain HTTP server for operational endpoints such as metrics. It
+//! only answers `GET` requests and closes the connection after each response.
+
+use async_trait::async_trait;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, error, info};
+
+/// Requests whose head is larger than this are rejected.
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// Time a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+#[async_trait]
+pub trait Endpoint: Send + Sync {
+    async fn respond(&self) -> Response;
+}
+
+pub struct HttpServer {
+    listener: TcpListener,
+    routes: HashMap<&'static str, Arc<dyn Endpoint>>,
+}
+
+impl HttpServer {
+    pub async fn bind(addr: SocketAddr) -> Result<Self, tokio::io::Error> {
+        info!("HTTP endpoints will listen on http://{addr}");
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            routes: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, tokio::io::Error> {
+        self.listener.local_addr()
+    }
+
+    pub fn route(&mut self, path: &'static str, endpoint: Arc<dyn Endpoint>) {
+        self.routes.insert(path, endpoint);
+    }
+
+    /// Serves requests until the returned future is dropped.
+    pub async fn serve(self) {
+        let routes = Arc::new(self.routes);
+        loop {
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                // Usually running out of file descriptors, see the accept loop
+                // of `ArkeServer::start`.
+                Err(err) => {
+                    error!("Couldn't accept HTTP connection: {err}");
+                    tokio::time::sleep(super::ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let routes = Arc::clone(&routes);
+            tokio::spawn(async move {
+                if let Err(err) = Self::handle(stream, &routes).await {
+                    debug!("HTTP request from {peer_addr} failed: {err:?}");
+                }
+            });
+        }
+    }
+
+    async fn handle(
+        mut stream: TcpStream,
+        routes: &HashMap<&'static str, Arc<dyn Endpoint>>,
+    ) -> Result<(), tokio::io::Error> {
+        let request_line = tokio::time::timeout(REQUEST_TIMEOUT, Self::read_head(&mut stream))
+            .await
+            .map_err(|_| {
+                tokio::io::Error::new(tokio::io::ErrorKind::TimedOut, "HTTP request timed out")
+            })??;
+
+        let mut parts = request_line.split_whitespace();
+        let response = match (parts.next(), parts.next()) {
+            (Some("GET"), Some(target)) => {
+                let path = target.split('?').next().unwrap_or_default();
+                match routes.get(path) {
+                    Some(endpoint) => endpoint.respond().await,
+                    None => Response::text(404, "Not found\n"),
+                }
+            }
+            (Some(_), Some(_)) => Response::text(405, "Method not allowed\n"),
+            _ => Response::text(400, "Bad request\n"),
+        };
+
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.reason(),
+            response.content_type,
+            response.body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&response.body).await?;
+        stream.shutdown().await
+    }
+
+    /// Reads the request head and returns its first line. Headers are ignored.
+    async fn read_head(stream: &mut TcpStream) -> Result<String, tokio::io::Error> {
+        let mut reader = BufReader::new(stream).take(MAX_REQUEST_SIZE);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+                break;
+            }
+        }
+        Ok(request_line)
+    }
+}
@@ -0,0 +1,180 @@
+//! Prometheus metrics, served in the text exposition format on a separate plain
+//! HTTP listener, see [`ArkeServerBuilder::with_metrics_addr`](super::ArkeServerBuilder::with_metrics_addr).
+
+use super::{
+    command::ArkeCommand,
+    http::{Endpoint, Response},
+};
+use crate::user::User;
+use async_trait::async_trait;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use sqlx::mysql::MySqlPool;
+use std::time::Duration;
+use tracing::error;
+
+pub struct Metrics {
+    registry: Registry,
+    pub connections_accepted: IntCounter,
+    pub connections_refused: IntCounter,
+    pub connections_open: IntGauge,
+    /// Received commands by discriminant.
+    pub commands: IntCounterVec,
+    /// Time spent in command handlers by discriminant.
+    pub handler_duration: HistogramVec,
+    /// Errors sent to clients by `CommandError` variant.
+    pub errors: IntCounterVec,
+    pub db_connections: IntGauge,
+    pub db_idle_connections: IntGauge,
+    /// One-time prekeys left across all users.
+    pub prekeys_available: IntGauge,
+    /// Users that have run out of one-time prekeys.
+    pub users_without_prekeys: IntGauge,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("arke".to_string()), None).expect("Invalid metrics prefix");
+
+        let metrics = Self {
+            connections_accepted: IntCounter::new(
+                "connections_accepted_total",
+                "Accepted TCP connections",
+            )
+            .unwrap(),
+            connections_refused: IntCounter::new(
+                "connections_refused_total",
+                "Connections refused because of connection limits",
+            )
+            .unwrap(),
+            connections_open: IntGauge::new("connections_open", "Currently open connections")
+                .unwrap(),
+            commands: IntCounterVec::new(
+                Opts::new(
+                    "commands_total",
+                    "Received commands by discriminant and outcome",
+                ),
+                &["command", "outcome"],
+            )
+            .unwrap(),
+            handler_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "handler_duration_seconds",
+                    "Time spent handling commands by discriminant",
+                ),
+                &["command"],
+            )
+            .unwrap(),
+            errors: IntCounterVec::new(
+                Opts::new("errors_total", "Errors sent to clients by type"),
+                &["error"],
+            )
+            .unwrap(),
+            db_connections: IntGauge::new("db_connections", "Open database connections").unwrap(),
+            db_idle_connections: IntGauge::new("db_idle_connections", "Idle database connections")
+                .unwrap(),
+            prekeys_available: IntGauge::new(
+                "prekeys_available",
+                "One-time prekeys left across all users",
+            )
+            .unwrap(),
+            users_without_prekeys: IntGauge::new(
+                "users_without_prekeys",
+                "Users that have run out of one-time prekeys",
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 10] = [
+            Box::new(metrics.connections_accepted.clone()),
+            Box::new(metrics.connections_refused.clone()),
+            Box::new(metrics.connections_open.clone()),
+            Box::new(metrics.commands.clone()),
+            Box::new(metrics.handler_duration.clone()),
+            Box::new(metrics.errors.clone()),
+            Box::new(metrics.db_connections.clone()),
+            Box::new(metrics.db_idle_connections.clone()),
+            Box::new(metrics.prekeys_available.clone()),
+            Box::new(metrics.users_without_prekeys.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("Duplicate metric");
+        }
+
+        metrics
+    }
+
+    /// Counts a command that was handled, or answered directly like `Ping`.
+    pub fn observe_command(&self, discriminant: u8, duration: Option<Duration>) {
+        let label = discriminant.to_string();
+        self.commands.with_label_values(&[&label, "handled"]).inc();
+        if let Some(duration) = duration {
+            self.handler_duration
+                .with_label_values(&[&label])
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    /// Counts a command that was refused by its rate limit without being handled.
+    pub fn observe_rate_limited(&self, discriminant: u8) {
+        self.commands
+            .with_label_values(&[&discriminant.to_string(), "rate_limited"])
+            .inc();
+    }
+
+    /// Counts the error carried by a command sent to a client, if any.
+    pub fn observe_response(&self, command: &ArkeCommand) {
+        if let ArkeCommand::Error(err) | ArkeCommand::Goodbye(Some(err)) = command {
+            self.errors.with_label_values(&[err.name()]).inc();
+        }
+    }
+
+    /// Updates the database pool and prekey gauges.
+    pub async fn sample_db(&self, db: &MySqlPool) -> Result<(), sqlx::Error> {
+        self.db_connections.set(db.size().into());
+        self.db_idle_connections.set(db.num_idle() as i64);
+
+        let stats = User::prekey_stats(db).await?;
+        self.prekeys_available.set(stats.available as i64);
+        self.users_without_prekeys.set(stats.exhausted as i64);
+        Ok(())
+    }
+
+    pub fn render(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Couldn't encode metrics: {err:?}");
+        }
+        buffer
+    }
+}
+
+#[async_trait]
+impl Endpoint for Metrics {
+    async fn respond(&self) -> Response {
+        Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: self.render(),
+        }
+    }
+}
@@ -1,25 +1,154 @@
 pub mod command;
+pub mod connection;
 pub mod db;
+pub mod health;
+pub mod http;
+pub mod metrics;
+pub mod ratelimit;
 pub mod state;
+pub mod tls;
 
-use command::{ArkeCommand, CommandHandler};
-use log::{debug, error, info};
+use command::{ArkeCommand, CommandError, CommandHandler};
+use connection::{Connection, ConnectionLimits, ConnectionTracker};
+use health::{Liveness, Readiness};
+use http::HttpServer;
+use metrics::Metrics;
+use ratelimit::{RateLimit, RateLimitKey, RateLimiter};
+use sqlx::mysql::MySqlPool;
 use std::{
-    net::{IpAddr, Ipv4Addr},
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    task::Poll,
+    time::Duration,
 };
+use tls::{ReloadableCert, TlsReloader, TlsSettings};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{mpsc, watch, Mutex},
+    task::JoinSet,
 };
-use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+use tokio_rustls::{rustls, TlsAcceptor};
+use tracing::{debug, error, field, info, info_span, Instrument, Span};
 
-pub struct ArkeServer {
+/// Commands are exchanged as newline terminated JSON frames of at most this many bytes.
+pub const MAX_FRAME_SIZE: usize = 128 * 1024;
+
+/// How long a shutdown waits for open connections to finish by default.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pause after a failed `accept`, e.g. when the process is out of file descriptors.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Time allowed for the TLS handshake.
+    pub handshake: Duration,
+    /// Connections are closed if no command arrives within this time.
+    pub idle: Duration,
+    /// Connections are closed once they have been open this long.
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Timeouts {
+    /// When a connection that was active just now becomes idle, or reaches its
+    /// lifetime `expires_at` if that comes first.
+    fn idle_deadline(&self, expires_at: Option<tokio::time::Instant>) -> tokio::time::Instant {
+        let deadline = tokio::time::Instant::now() + self.idle;
+        expires_at.map_or(deadline, |expires_at| deadline.min(expires_at))
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: Duration::from_secs(10),
+            idle: Duration::from_secs(5 * 60),
+            max_lifetime: None,
+        }
+    }
+}
+
+/// Stops a running [`ArkeServer`]. Open connections are sent a
+/// `Goodbye(ShuttingDown)` once their current command has been handled.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+/// An address the server accepts connections on and the TLS settings used for
+/// connections to it.
+#[derive(Debug, Clone)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    /// Accept IPv4 connections on an IPv6 wildcard address as well. Has no
+    /// effect for IPv4 addresses.
+    pub dual_stack: bool,
+    pub tls: TlsSettings,
+}
+
+impl Listener {
+    pub fn new(addr: SocketAddr, tls: TlsSettings) -> Self {
+        Self {
+            addr,
+            dual_stack: false,
+            tls,
+        }
+    }
+
+    fn bind(&self) -> Result<TcpListener, tokio::io::Error> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(
+            Domain::for_address(self.addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        if self.addr.is_ipv6() {
+            socket.set_only_v6(!self.dual_stack)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&self.addr.into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    }
+}
+
+struct BoundListener {
     listener: TcpListener,
-    certs: Vec<rustls::Certificate>,
-    private_key: rustls::PrivateKey,
+    tls: TlsSettings,
+}
+
+/// State shared between the accept loop and every connection task.
+struct ConnectionContext {
+    handlers: Mutex<HashMap<u8, Box<dyn CommandHandler>>>,
+    rate_limiter: std::sync::Mutex<RateLimiter>,
+    tracker: Arc<std::sync::Mutex<ConnectionTracker>>,
+    timeouts: Timeouts,
+    limits: ConnectionLimits,
+    metrics: Option<Arc<Metrics>>,
+}
+
+pub struct ArkeServer {
+    listeners: Vec<BoundListener>,
+    metrics: Option<Arc<Metrics>>,
+    readiness: Option<Arc<Readiness>>,
+    /// Plain HTTP listeners for metrics and health checks.
+    http_listeners: Vec<HttpServer>,
     handlers: HashMap<u8, Box<dyn CommandHandler>>,
+    rate_limiter: RateLimiter,
+    timeouts: Timeouts,
+    limits: ConnectionLimits,
+    tls_reload_interval: Option<Duration>,
+    shutdown_timeout: Duration,
+    shutdown: ShutdownHandle,
 }
 
 impl ArkeServer {
@@ -29,7 +158,17 @@ impl ArkeServer {
             bind_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
             certs: vec![],
             private_key: None,
+            server_certs: HashMap::new(),
             handlers: None,
+            listeners: vec![],
+            rate_limits: HashMap::new(),
+            timeouts: Timeouts::default(),
+            limits: ConnectionLimits::default(),
+            tls_reload_interval: Some(tls::DEFAULT_RELOAD_INTERVAL),
+            metrics_addr: None,
+            health_addr: None,
+            health_db: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
         }
     }
 
@@ -40,93 +179,486 @@ impl ArkeServer {
         private_key: rustls::PrivateKey,
         handlers: HashMap<u8, Box<dyn CommandHandler>>,
     ) -> Result<Self, tokio::io::Error> {
-        let bind_addr = format!("{}:{}", bind_addr, bind_port);
-        info!("Server will listen on tcp://{bind_addr}");
+        let tls = TlsSettings::new(certs, private_key)
+            .map_err(|err| tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, err))?;
+        let listener = Listener::new(SocketAddr::new(bind_addr, bind_port), tls);
+        Self::bind(vec![listener], handlers).await
+    }
+
+    /// Binds every listener, all of which share the same handlers.
+    pub async fn bind(
+        listeners: Vec<Listener>,
+        handlers: HashMap<u8, Box<dyn CommandHandler>>,
+    ) -> Result<Self, tokio::io::Error> {
+        let listeners = listeners
+            .into_iter()
+            .map(|listener| {
+                info!("Server will listen on tcp://{}", listener.addr);
+                Ok(BoundListener {
+                    listener: listener.bind()?,
+                    tls: listener.tls,
+                })
+            })
+            .collect::<Result<Vec<_>, tokio::io::Error>>()?;
+
         Ok(Self {
-            listener: TcpListener::bind(bind_addr).await?,
-            certs,
-            private_key,
+            listeners,
+            metrics: None,
+            readiness: None,
+            http_listeners: vec![],
             handlers,
+            rate_limiter: RateLimiter::default(),
+            timeouts: Timeouts::default(),
+            limits: ConnectionLimits::default(),
+            tls_reload_interval: Some(tls::DEFAULT_RELOAD_INTERVAL),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            shutdown: ShutdownHandle {
+                sender: Arc::new(watch::channel(false).0),
+            },
         })
     }
 
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Metrics collected by the server, if a metrics listener was configured.
+    pub fn metrics(&self) -> Option<Arc<Metrics>> {
+        self.metrics.clone()
+    }
+
+    /// Returns a handle that reloads the certificates of all listeners, e.g. on SIGHUP.
+    pub fn tls_reloader(&self) -> TlsReloader {
+        TlsReloader::new(self.listeners.iter().map(|bound| &bound.tls))
+    }
+
+    /// Addresses the server is listening on, with ports chosen by the OS resolved.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, tokio::io::Error> {
+        self.listeners
+            .iter()
+            .map(|bound| bound.listener.local_addr())
+            .collect()
+    }
+
+    /// Accepts a connection on whichever listener has one ready first and
+    /// returns the index of that listener alongside it. Listeners are polled
+    /// starting at `start`, so that callers can rotate it to keep a busy
+    /// listener from starving the ones after it.
+    async fn accept(
+        listeners: &[BoundListener],
+        start: usize,
+    ) -> Result<(TcpStream, SocketAddr, usize), tokio::io::Error> {
+        std::future::poll_fn(|cx| {
+            for offset in 0..listeners.len() {
+                let index = (start + offset) % listeners.len();
+                if let Poll::Ready(accepted) = listeners[index].listener.poll_accept(cx) {
+                    return Poll::Ready(accepted.map(|(socket, addr)| (socket, addr, index)));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
     async fn handle_connection(
         stream: TcpStream,
         acceptor: TlsAcceptor,
-        handlers: Arc<Mutex<HashMap<u8, Box<dyn CommandHandler>>>>,
+        context: Arc<ConnectionContext>,
+        mut shutdown: watch::Receiver<bool>,
     ) -> Result<(), tokio::io::Error> {
         let peer_addr = stream.peer_addr()?;
-        let mut stream = acceptor.accept(stream).await?;
+        let timeouts = context.timeouts;
+        let slot = ConnectionTracker::acquire(&context.tracker, peer_addr.ip(), &context.limits);
 
-        'connection: loop {
-            let mut buffer = [0; 4096];
-            let n = stream.read(&mut buffer).await?;
+        if let (None, Some(metrics)) = (&slot, &context.metrics) {
+            metrics.connections_refused.inc();
+        }
 
-            match serde_json::from_slice::<ArkeCommand>(&buffer[..n]) {
-                Ok(command) => {
-                    debug!(
-                        "Received command with discriminant: {}",
-                        command.discriminant()
-                    );
+        if slot.is_none() && context.limits.refuse_before_tls {
+            info!("Refusing connection from {peer_addr}, server is busy");
+            return Self::refuse(stream, timeouts.handshake).await;
+        }
+
+        let stream = tokio::time::timeout(timeouts.handshake, acceptor.accept(stream))
+            .await
+            .map_err(|_| {
+                tokio::io::Error::new(tokio::io::ErrorKind::TimedOut, "TLS handshake timed out")
+            })??;
+
+        let Some(_slot) = slot else {
+            info!("Refusing connection from {peer_addr}, server is busy");
+            return Self::refuse(stream, timeouts.handshake).await;
+        };
+
+        let client_subject = tls::client_subject(stream.get_ref().1);
+        if let Some(subject) = &client_subject {
+            info!("Connection {peer_addr} presented client certificate {subject}");
+        }
+
+        let (reader, writer) = tokio::io::split(stream);
+        let mut stream = BufReader::new(reader);
+        let (outbound, queue) = mpsc::channel(context.limits.outbound_queue_size);
+        let expires_at = timeouts
+            .max_lifetime
+            .map(|lifetime| tokio::time::Instant::now() + lifetime);
+        let writer = tokio::spawn(
+            Self::write_queued(writer, queue, context.metrics.clone(), timeouts, expires_at)
+                .in_current_span(),
+        );
+        let span = Span::current();
+
+        let mut connection = Connection::new(peer_addr);
+        connection.client_subject = client_subject;
+
+        let served: Result<(), tokio::io::Error> = async {
+            'connection: loop {
+                let deadline = timeouts.idle_deadline(expires_at);
 
-                    let mut handlers = handlers.lock().await;
-                    let handler = handlers.get_mut(&command.discriminant());
-                    let result = handler
-                        .expect("Expected command handler to be present")
-                        .handle(command)
-                        .await;
+                let read = tokio::select! {
+                    read = tokio::time::timeout_at(deadline, Self::read_frame(&mut stream)) => Some(read),
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => None,
+                    // The writer gave up on the client, see `write_queued`.
+                    _ = outbound.closed() => break 'connection,
+                };
 
-                    if let ArkeCommand::Goodbye(err) = result {
-                        log::info!("Sending Goodbye(Error = {err:?}) for connection {peer_addr}");
+                let Some(read) = read else {
+                    info!("Server shutting down, closing connection {peer_addr}");
+                    let goodbye = ArkeCommand::Goodbye(Some(CommandError::ShuttingDown));
+                    Self::queue_command(&outbound, goodbye, deadline).await?;
+                    break 'connection;
+                };
+
+                let frame = match read {
+                    Ok(Ok(Some(frame))) => frame,
+                    Ok(Ok(None)) => break 'connection,
+                    Ok(Err(err)) if err.kind() == tokio::io::ErrorKind::InvalidData => {
+                        error!("Invalid frame from {peer_addr}. {err:?}");
+                        Self::queue_command(&outbound, ArkeCommand::Goodbye(None), deadline).await?;
+                        break 'connection;
+                    }
+                    Ok(Err(err)) => return Err(err),
+                    Err(_) => {
+                        info!("Connection {peer_addr} timed out");
+                        let goodbye = ArkeCommand::Goodbye(Some(CommandError::Timeout));
+                        Self::queue_command(&outbound, goodbye, deadline).await?;
+                        break 'connection;
+                    }
+                };
+                let deadline = timeouts.idle_deadline(expires_at);
+
+                match serde_json::from_slice::<ArkeCommand>(&frame) {
+                    Ok(command) => {
+                        let discriminant = command.discriminant();
+                        debug!("Received command with discriminant: {discriminant}");
+
+                        if let Err(retry_after) = Self::check_rate_limit(
+                            &context.rate_limiter,
+                            &connection,
+                            command.discriminant(),
+                        ) {
+                            debug!("Rate limiting connection {peer_addr}");
+                            if let Some(metrics) = &context.metrics {
+                                metrics.observe_rate_limited(discriminant);
+                            }
+                            let err = CommandError::RateLimited {
+                                retry_after: retry_after.as_secs_f64().ceil() as u64,
+                            };
+                            Self::queue_command(&outbound, ArkeCommand::Error(err), deadline).await?;
+                            continue 'connection;
+                        }
+
+                        if let ArkeCommand::Ping(payload) = command {
+                            if let Some(metrics) = &context.metrics {
+                                metrics.observe_command(discriminant, None);
+                            }
+                            Self::queue_command(&outbound, ArkeCommand::Pong(payload), deadline).await?;
+                            continue 'connection;
+                        }
+
+                        let started = std::time::Instant::now();
+                        let mut handlers = context.handlers.lock().await;
+                        let result = match handlers.get_mut(&discriminant) {
+                            Some(handler) => {
+                                handler
+                                    .handle(&mut connection, command)
+                                    .instrument(info_span!("command", discriminant))
+                                    .await
+                            }
+                            None => ArkeCommand::Error(CommandError::InvalidRequest {
+                                msg: "Unsupported command".to_string(),
+                            }),
+                        };
+                        drop(handlers);
+                        Self::record_connection(&span, &connection);
+
+                        if let Some(metrics) = &context.metrics {
+                            metrics.observe_command(discriminant, Some(started.elapsed()));
+                        }
+
+                        if let ArkeCommand::Goodbye(err) = result {
+                            tracing::info!(
+                                "Sending Goodbye(Error = {err:?}) for connection {peer_addr}"
+                            );
+                            Self::queue_command(&outbound, ArkeCommand::Goodbye(err), deadline).await?;
+                            break 'connection;
+                        } else {
+                            Self::queue_command(&outbound, result, deadline).await?;
+                        }
+                    }
+                    Err(err) => {
+                        error!("Invalid command. {err:?}");
+                        Self::queue_command(&outbound, ArkeCommand::Goodbye(None), deadline).await?;
                         break 'connection;
-                    } else {
-                        Self::send_command(&mut stream, result).await?;
                     }
-                }
-                Err(err) => {
-                    error!("Invalid command. {err:?}");
-                    Self::send_command(&mut stream, ArkeCommand::Goodbye(None)).await?;
-                    break 'connection;
                 }
             }
+            Ok(())
         }
+        .await;
 
-        stream.shutdown().await?;
+        // Closing the queue lets the writer flush what is left and shut down the stream.
+        drop(outbound);
+        let written = writer.await.map_err(tokio::io::Error::other)?;
+        served?;
+        written?;
 
         info!("Closing connection from {}", peer_addr);
         Ok(())
     }
 
+    /// Adds what handlers have learned about the connection to its span.
+    fn record_connection(span: &Span, connection: &Connection) {
+        if let Some(user) = &connection.user {
+            span.record("user", field::display(user));
+        }
+        if let Some((major, minor, patch)) = connection.version {
+            span.record(
+                "version",
+                field::display(format_args!("{major}.{minor}.{patch}")),
+            );
+        }
+    }
+
+    /// Sends `Goodbye(ServerBusy)` to a connection that would exceed the
+    /// configured limits and closes it, giving up after `timeout`.
+    async fn refuse(mut stream: impl AsyncWrite + Unpin, timeout: Duration) -> Result<(), tokio::io::Error> {
+        let goodbye = ArkeCommand::Goodbye(Some(CommandError::ServerBusy));
+        tokio::time::timeout(timeout, async {
+            Self::send_command(&mut stream, goodbye).await?;
+            stream.shutdown().await
+        })
+        .await
+        .map_err(|_| tokio::io::Error::new(tokio::io::ErrorKind::TimedOut, "Refusal timed out"))?
+    }
+
+    /// Queues a command for the connection's writer task. Waits while the queue
+    /// is full, so a slow reader stops the server from reading further commands,
+    /// but no longer than until `deadline`.
+    async fn queue_command(
+        outbound: &mpsc::Sender<ArkeCommand>,
+        command: ArkeCommand,
+        deadline: tokio::time::Instant,
+    ) -> Result<(), tokio::io::Error> {
+        match tokio::time::timeout_at(deadline, outbound.send(command)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::BrokenPipe,
+                "Connection writer closed",
+            )),
+            Err(_) => Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::TimedOut,
+                "Client didn't read queued commands in time",
+            )),
+        }
+    }
+
+    /// Writes queued commands to the client. Each write must finish within the
+    /// idle timeout and before the connection's lifetime ends, otherwise the
+    /// writer stops and the connection is dropped.
+    async fn write_queued(
+        mut stream: impl AsyncWrite + Unpin,
+        mut queue: mpsc::Receiver<ArkeCommand>,
+        metrics: Option<Arc<Metrics>>,
+        timeouts: Timeouts,
+        expires_at: Option<tokio::time::Instant>,
+    ) -> Result<(), tokio::io::Error> {
+        let timed_out = |_| {
+            tokio::io::Error::new(tokio::io::ErrorKind::TimedOut, "Client didn't read in time")
+        };
+
+        while let Some(command) = queue.recv().await {
+            if let Some(metrics) = &metrics {
+                metrics.observe_response(&command);
+            }
+            tokio::time::timeout_at(timeouts.idle_deadline(expires_at), Self::send_command(&mut stream, command))
+                .await
+                .map_err(timed_out)??;
+        }
+        tokio::time::timeout_at(timeouts.idle_deadline(expires_at), stream.shutdown())
+            .await
+            .map_err(timed_out)?
+    }
+
+    /// Applies the rate limit of a command both to the peer's address and, once
+    /// authenticated, to the user.
+    fn check_rate_limit(
+        rate_limiter: &std::sync::Mutex<RateLimiter>,
+        connection: &Connection,
+        discriminant: u8,
+    ) -> Result<(), std::time::Duration> {
+        let mut rate_limiter = rate_limiter.lock().expect("Rate limiter lock poisoned");
+        rate_limiter.check(discriminant, RateLimitKey::peer(connection.peer_addr.ip()))?;
+        if let Some(user) = &connection.user {
+            rate_limiter.check(discriminant, RateLimitKey::User(user.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// Reads a single newline terminated frame. Returns `None` once the peer has
+    /// closed the connection.
+    async fn read_frame(
+        stream: &mut (impl AsyncBufRead + Unpin),
+    ) -> Result<Option<Vec<u8>>, tokio::io::Error> {
+        let mut frame = Vec::new();
+        let n = (&mut *stream)
+            .take(MAX_FRAME_SIZE as u64)
+            .read_until(b'\n', &mut frame)
+            .await?;
+
+        if n == 0 {
+            Ok(None)
+        } else if n == MAX_FRAME_SIZE && frame.last() != Some(&b'\n') {
+            Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidData,
+                "Frame exceeds maximum size",
+            ))
+        } else {
+            Ok(Some(frame))
+        }
+    }
+
     async fn send_command(
-        stream: &mut TlsStream<TcpStream>,
+        stream: &mut (impl AsyncWrite + Unpin),
         command: ArkeCommand,
-    ) -> Result<usize, tokio::io::Error> {
+    ) -> Result<(), tokio::io::Error> {
         let mut msg = serde_json::to_vec(&command).expect("Couldn't serialize message");
         msg.push("\n".as_bytes()[0]);
         debug!("Sending command: {command:?}");
-        stream.write(&msg).await
+        stream.write_all(&msg).await?;
+        stream.flush().await
     }
 
     pub async fn start(self) -> Result<(), tokio::io::Error> {
-        let config = Arc::new(
-            rustls::ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(self.certs, self.private_key)
-                .expect("Couldn't create TLS config"),
-        );
+        let acceptors = self
+            .listeners
+            .iter()
+            .map(|bound| bound.tls.acceptor())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, err))?;
 
-        let acceptor = TlsAcceptor::from(Arc::clone(&config));
+        let reload_task = self.tls_reload_interval.map(|interval| {
+            let reloader = self.tls_reloader();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    reloader.reload_changed();
+                }
+            })
+        });
 
         info!("Starting Arke server...");
-        let handlers = Arc::new(Mutex::new(self.handlers));
+        let context = Arc::new(ConnectionContext {
+            handlers: Mutex::new(self.handlers),
+            rate_limiter: std::sync::Mutex::new(self.rate_limiter),
+            tracker: Arc::new(std::sync::Mutex::new(match &self.metrics {
+                Some(metrics) => ConnectionTracker::with_gauge(metrics.connections_open.clone()),
+                None => ConnectionTracker::default(),
+            })),
+            timeouts: self.timeouts,
+            limits: self.limits,
+            metrics: self.metrics.clone(),
+        });
+
+        let http_tasks = self
+            .http_listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(listener.serve()))
+            .collect::<Vec<_>>();
+        if let Some(readiness) = &self.readiness {
+            readiness.accepting.store(true, Ordering::Relaxed);
+        }
+        let mut shutdown = self.shutdown.sender.subscribe();
+        let mut connections = JoinSet::new();
+        let mut next_listener = 0;
         loop {
-            let acceptor = acceptor.clone();
-            let (socket, peer_addr) = self.listener.accept().await?;
-            info!("Accepting socket connection from {peer_addr}");
-            let handler = Arc::clone(&handlers);
-            tokio::spawn(async move { Self::handle_connection(socket, acceptor, handler).await });
+            tokio::select! {
+                accepted = Self::accept(&self.listeners, next_listener) => {
+                    let (socket, peer_addr, index) = match accepted {
+                        Ok(accepted) => accepted,
+                        // Usually running out of file descriptors. Wait for
+                        // some connections to close instead of exiting.
+                        Err(err) => {
+                            error!("Couldn't accept connection: {err}");
+                            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    next_listener = index + 1;
+                    info!("Accepting socket connection from {peer_addr}");
+                    if let Some(metrics) = &context.metrics {
+                        metrics.connections_accepted.inc();
+                    }
+                    let acceptor = acceptors[index].clone();
+                    let context = Arc::clone(&context);
+                    let shutdown = self.shutdown.sender.subscribe();
+                    let span = info_span!(
+                        "connection",
+                        peer = %peer_addr,
+                        user = field::Empty,
+                        version = field::Empty
+                    );
+                    connections.spawn(
+                        Self::handle_connection(socket, acceptor, context, shutdown).instrument(span),
+                    );
+                }
+                Some(result) = connections.join_next(), if !connections.is_empty() => {
+                    if let Ok(Err(err)) = result {
+                        debug!("Connection closed with error: {err:?}");
+                    }
+                }
+                _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            }
+        }
+
+        if let Some(readiness) = &self.readiness {
+            readiness.accepting.store(false, Ordering::Relaxed);
+        }
+        drop(self.listeners);
+        info!(
+            "Stopped accepting connections, waiting for {} open connections to close",
+            connections.len()
+        );
+
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            tracing::warn!(
+                "Aborting {} connections that didn't close in time",
+                connections.len()
+            );
+            connections.shutdown().await;
+        }
+
+        for task in reload_task.into_iter().chain(http_tasks) {
+            task.abort();
         }
+
+        info!("Arke server stopped");
+        Ok(())
     }
 }
 
@@ -136,7 +668,17 @@ pub struct ArkeServerBuilder {
     bind_addr: IpAddr,
     certs: Vec<rustls::Certificate>,
     private_key: Option<rustls::PrivateKey>,
+    server_certs: HashMap<String, (Vec<rustls::Certificate>, rustls::PrivateKey)>,
     handlers: Option<HashMap<u8, Box<dyn CommandHandler>>>,
+    listeners: Vec<Listener>,
+    rate_limits: HashMap<u8, RateLimit>,
+    timeouts: Timeouts,
+    limits: ConnectionLimits,
+    tls_reload_interval: Option<Duration>,
+    metrics_addr: Option<SocketAddr>,
+    health_addr: Option<SocketAddr>,
+    health_db: Option<MySqlPool>,
+    shutdown_timeout: Duration,
 }
 
 impl ArkeServerBuilder {
@@ -150,6 +692,28 @@ impl ArkeServerBuilder {
         self
     }
 
+    /// Presents `certs` to clients that ask for `server_name` via SNI. Clients
+    /// asking for any other name get the certificates set with [`Self::with_certs`].
+    pub fn with_server_cert(
+        mut self,
+        server_name: impl Into<String>,
+        certs: Vec<rustls::Certificate>,
+        private_key: rustls::PrivateKey,
+    ) -> Self {
+        self.server_certs
+            .insert(server_name.into(), (certs, private_key));
+        self
+    }
+
+    /// Like [`Self::with_server_cert`] for a whole map of server names.
+    pub fn with_server_certs(
+        mut self,
+        server_certs: HashMap<String, (Vec<rustls::Certificate>, rustls::PrivateKey)>,
+    ) -> Self {
+        self.server_certs.extend(server_certs);
+        self
+    }
+
     pub fn with_bind_addr(mut self, bind_addr: IpAddr) -> Self {
         self.bind_addr = bind_addr;
         self
@@ -160,15 +724,152 @@ impl ArkeServerBuilder {
         self
     }
 
+    /// Adds a listener with its own address and TLS settings. Once any listener
+    /// is added, the bind address, port and certificates set on the builder are
+    /// no longer used.
+    pub fn with_listener(mut self, listener: Listener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Limits how often a single peer address or user may send the command
+    /// constructed by `command`, e.g. `with_rate_limit(ArkeCommand::CreateUser, limit)`.
+    pub fn with_rate_limit<T: Default>(mut self, command: fn(T) -> ArkeCommand, limit: RateLimit) -> Self {
+        self.rate_limits
+            .insert(command(Default::default()).discriminant(), limit);
+        self
+    }
+
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.idle = timeout;
+        self
+    }
+
+    pub fn with_max_connection_lifetime(mut self, lifetime: Duration) -> Self {
+        self.timeouts.max_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Refuses new connections with `Goodbye(ServerBusy)` while this many are open.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.limits.max_connections = Some(max);
+        self
+    }
+
+    /// Refuses new connections with `Goodbye(ServerBusy)` while this many are
+    /// open from the same peer address.
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> Self {
+        self.limits.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Refuse excess connections before the TLS handshake instead of after it.
+    pub fn with_refuse_before_tls(mut self, refuse_before_tls: bool) -> Self {
+        self.limits.refuse_before_tls = refuse_before_tls;
+        self
+    }
+
+    /// How many outgoing commands may be queued per connection before the server
+    /// stops reading from it.
+    pub fn with_outbound_queue_size(mut self, size: usize) -> Self {
+        self.limits.outbound_queue_size = size.max(1);
+        self
+    }
+
+    /// How often certificate files are checked for changes and reloaded. `None`
+    /// disables the check; certificates can still be reloaded through
+    /// [`ArkeServer::tls_reloader`].
+    pub fn with_tls_reload_interval(mut self, interval: Option<Duration>) -> Self {
+        self.tls_reload_interval = interval;
+        self
+    }
+
+    /// Serves Prometheus metrics over plain HTTP at `/metrics` on this address.
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Serves liveness and readiness probes over plain HTTP at `/healthz` and
+    /// `/readyz` on this address. It may be the same as the metrics address.
+    pub fn with_health_addr(mut self, addr: SocketAddr) -> Self {
+        self.health_addr = Some(addr);
+        self
+    }
+
+    /// Database `/readyz` checks for reachability and pending migrations.
+    pub fn with_health_db(mut self, db: MySqlPool) -> Self {
+        self.health_db = Some(db);
+        self
+    }
+
+    /// How long [`ArkeServer::start`] waits for open connections to close after
+    /// a shutdown before aborting them.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
     pub async fn build(self) -> Result<ArkeServer, tokio::io::Error> {
-        Ok(ArkeServer::new(
-            self.bind_port,
-            self.bind_addr,
-            self.certs,
-            self.private_key.unwrap(),
-            self.handlers.unwrap(),
-        )
-        .await?)
+        let mut server = if !self.listeners.is_empty() {
+            ArkeServer::bind(self.listeners, self.handlers.unwrap()).await?
+        } else if !self.server_certs.is_empty() {
+            let invalid_input =
+                |err| tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, err);
+            let mut tls =
+                TlsSettings::new(self.certs, self.private_key.unwrap()).map_err(invalid_input)?;
+            for (server_name, (certs, private_key)) in self.server_certs {
+                let cert = ReloadableCert::new(certs, private_key).map_err(invalid_input)?;
+                tls = tls.with_server_name(&server_name, cert);
+            }
+
+            let listener = Listener::new(SocketAddr::new(self.bind_addr, self.bind_port), tls);
+            ArkeServer::bind(vec![listener], self.handlers.unwrap()).await?
+        } else {
+            ArkeServer::new(
+                self.bind_port,
+                self.bind_addr,
+                self.certs,
+                self.private_key.unwrap(),
+                self.handlers.unwrap(),
+            )
+            .await?
+        };
+
+        server.rate_limiter = RateLimiter::new(self.rate_limits);
+        server.timeouts = self.timeouts;
+        server.limits = self.limits;
+        server.tls_reload_interval = self.tls_reload_interval;
+
+        let mut http_addrs = vec![];
+        if let Some(addr) = self.metrics_addr {
+            let metrics = Arc::new(Metrics::new());
+            let mut listener = HttpServer::bind(addr).await?;
+            listener.route("/metrics", metrics.clone());
+            server.metrics = Some(metrics);
+            server.http_listeners.push(listener);
+            http_addrs.push(addr);
+        }
+        if let Some(addr) = self.health_addr {
+            let readiness = Arc::new(Readiness::new(self.health_db));
+            let listener = match http_addrs.iter().position(|bound| *bound == addr) {
+                Some(index) => &mut server.http_listeners[index],
+                None => {
+                    server.http_listeners.push(HttpServer::bind(addr).await?);
+                    server.http_listeners.last_mut().unwrap()
+                }
+            };
+            listener.route("/healthz", Arc::new(Liveness));
+            listener.route("/readyz", readiness.clone());
+            server.readiness = Some(readiness);
+        }
+        server.shutdown_timeout = self.shutdown_timeout;
+        Ok(server)
     }
 
     pub fn handlers(mut self, handlers: HashMap<u8, Box<dyn CommandHandler>>) -> Self {
@@ -176,3 +877,62 @@ impl ArkeServerBuilder {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts(idle: Duration) -> Timeouts {
+        Timeouts {
+            idle,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn writer_gives_up_on_a_client_that_doesnt_read() {
+        let (client, server) = tokio::io::duplex(64);
+        let (outbound, queue) = mpsc::channel(4);
+        let writer = tokio::spawn(ArkeServer::write_queued(
+            server,
+            queue,
+            None,
+            timeouts(Duration::from_millis(100)),
+            None,
+        ));
+
+        outbound.send(ArkeCommand::Contacts(vec!["a".repeat(1024)])).await.unwrap();
+        let written = tokio::time::timeout(Duration::from_secs(5), writer).await.unwrap().unwrap();
+        assert_eq!(written.unwrap_err().kind(), tokio::io::ErrorKind::TimedOut);
+        assert!(outbound.is_closed());
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn queueing_gives_up_at_the_deadline() {
+        let (outbound, _queue) = mpsc::channel(1);
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(100);
+        ArkeServer::queue_command(&outbound, ArkeCommand::Pong(0), deadline).await.unwrap();
+
+        let queued = ArkeServer::queue_command(&outbound, ArkeCommand::Pong(0), deadline).await;
+        assert_eq!(queued.unwrap_err().kind(), tokio::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn lifetime_ends_writes() {
+        let (_client, server) = tokio::io::duplex(64);
+        let (outbound, queue) = mpsc::channel(4);
+        let expires_at = tokio::time::Instant::now() + Duration::from_millis(100);
+        let writer = tokio::spawn(ArkeServer::write_queued(
+            server,
+            queue,
+            None,
+            timeouts(Duration::from_secs(60)),
+            Some(expires_at),
+        ));
+
+        outbound.send(ArkeCommand::Contacts(vec!["a".repeat(1024)])).await.unwrap();
+        let written = tokio::time::timeout(Duration::from_secs(5), writer).await.unwrap().unwrap();
+        assert_eq!(written.unwrap_err().kind(), tokio::io::ErrorKind::TimedOut);
+    }
+}
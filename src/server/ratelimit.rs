@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Token bucket parameters. A client may send up to `burst` commands at once,
+/// after which one more is allowed every `interval`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, interval: Duration) -> Self {
+        Self { burst, interval }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    /// Built with [`RateLimitKey::peer`].
+    Peer(IpAddr),
+    User(String),
+}
+
+impl RateLimitKey {
+    /// Key for a client connecting from `addr`, see [`crate::peer_key`].
+    pub fn peer(addr: IpAddr) -> Self {
+        Self::Peer(crate::peer_key(addr))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets are dropped once they have refilled, but only when there are more
+/// than this many of them, and at most once per [`SWEEP_INTERVAL`].
+const MAX_IDLE_BUCKETS: usize = 10_000;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: HashMap<u8, RateLimit>,
+    buckets: HashMap<(u8, RateLimitKey), Bucket>,
+    last_sweep: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(HashMap::new())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<u8, RateLimit>) -> Self {
+        Self {
+            limits,
+            buckets: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Takes a token for the command with `discriminant` from the bucket of
+    /// `key`. If the bucket is empty, returns how long to wait for the next token.
+    pub fn check(&mut self, discriminant: u8, key: RateLimitKey) -> Result<(), Duration> {
+        self.check_at(discriminant, key, Instant::now())
+    }
+
+    fn check_at(&mut self, discriminant: u8, key: RateLimitKey, now: Instant) -> Result<(), Duration> {
+        let Some(limit) = self.limits.get(&discriminant).copied() else {
+            return Ok(());
+        };
+
+        if self.buckets.len() > MAX_IDLE_BUCKETS && now.duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.buckets.retain(|(d, _), bucket| {
+                let limit = self.limits[d];
+                Self::refill(bucket, limit, now) < limit.burst as f64
+            });
+            self.last_sweep = now;
+        }
+
+        let bucket = self.buckets.entry((discriminant, key)).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+
+        let tokens = Self::refill(bucket, limit, now);
+        bucket.tokens = tokens;
+        bucket.updated = now;
+
+        if tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(limit.interval.mul_f64(1.0 - tokens))
+        }
+    }
+
+    fn refill(bucket: &Bucket, limit: RateLimit, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        let refilled = elapsed / limit.interval.as_secs_f64().max(f64::EPSILON);
+        (bucket.tokens + refilled).min(limit.burst as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMAND: u8 = 1;
+
+    fn limiter(burst: u32, interval: Duration) -> RateLimiter {
+        RateLimiter::new(HashMap::from([(COMMAND, RateLimit::new(burst, interval))]))
+    }
+
+    fn peer(last: u8) -> RateLimitKey {
+        RateLimitKey::Peer(IpAddr::from([192, 0, 2, last]))
+    }
+
+    #[test]
+    fn burst_then_refused() {
+        let mut limiter = limiter(3, Duration::from_secs(10));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(COMMAND, peer(1), now).is_ok());
+        }
+        assert_eq!(limiter.check_at(COMMAND, peer(1), now), Err(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let mut limiter = limiter(2, Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(limiter.check_at(COMMAND, peer(1), start).is_ok());
+        assert!(limiter.check_at(COMMAND, peer(1), start).is_ok());
+
+        let retry_after = limiter.check_at(COMMAND, peer(1), start + Duration::from_secs(4)).unwrap_err();
+        assert!(retry_after.abs_diff(Duration::from_secs(6)) < Duration::from_millis(1));
+
+        assert!(limiter.check_at(COMMAND, peer(1), start + Duration::from_secs(10)).is_ok());
+        assert!(limiter.check_at(COMMAND, peer(1), start + Duration::from_secs(10)).is_err());
+    }
+
+    #[test]
+    fn refill_is_capped_at_burst() {
+        let mut limiter = limiter(2, Duration::from_secs(1));
+        let start = Instant::now();
+        assert!(limiter.check_at(COMMAND, peer(1), start).is_ok());
+
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.check_at(COMMAND, peer(1), later).is_ok());
+        assert!(limiter.check_at(COMMAND, peer(1), later).is_ok());
+        assert!(limiter.check_at(COMMAND, peer(1), later).is_err());
+    }
+
+    #[test]
+    fn buckets_are_separate() {
+        let mut limiter = limiter(1, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(limiter.check_at(COMMAND, peer(1), now).is_ok());
+        assert!(limiter.check_at(COMMAND, peer(1), now).is_err());
+        assert!(limiter.check_at(COMMAND, peer(2), now).is_ok());
+        assert!(limiter.check_at(COMMAND, RateLimitKey::User("alice".to_string()), now).is_ok());
+        assert!(limiter.check_at(COMMAND + 1, peer(1), now).is_ok());
+    }
+
+    #[test]
+    fn ipv6_peers_share_their_64() {
+        let mut limiter = limiter(1, Duration::from_secs(60));
+        let now = Instant::now();
+        let addr = |last: u16| IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, last]);
+        assert!(limiter.check_at(COMMAND, RateLimitKey::peer(addr(1)), now).is_ok());
+        assert!(limiter.check_at(COMMAND, RateLimitKey::peer(addr(2)), now).is_err());
+
+        let other_network = IpAddr::from([0x2001, 0xdb8, 0, 2, 0, 0, 0, 1]);
+        assert!(limiter.check_at(COMMAND, RateLimitKey::peer(other_network), now).is_ok());
+    }
+
+    #[test]
+    fn idle_buckets_are_swept_once_per_interval() {
+        let mut limiter = limiter(1, Duration::from_secs(1));
+        let start = limiter.last_sweep;
+        for i in 0..=MAX_IDLE_BUCKETS {
+            let key = RateLimitKey::User(i.to_string());
+            assert!(limiter.check_at(COMMAND, key, start).is_ok());
+        }
+
+        // All buckets have refilled, but the last sweep is too recent.
+        let refilled = start + Duration::from_secs(10);
+        assert!(limiter.check_at(COMMAND, peer(1), refilled).is_ok());
+        assert_eq!(limiter.buckets.len(), MAX_IDLE_BUCKETS + 2);
+
+        assert!(limiter.check_at(COMMAND, peer(2), start + SWEEP_INTERVAL).is_ok());
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+}
@@ -1,18 +1,40 @@
+use crate::{
+    attachment::AttachmentStore, audit::UnknownUserFailures, crypto::SigningKey,
+    discovery::DiscoveryLimiter, registration::RegistrationGate, transparency::KeyLog,
+};
 use sqlx::mysql::MySqlPool;
+use std::time::Duration;
+
+/// Default period during which the username of a deleted account can't be
+/// registered again.
+pub const DEFAULT_USERNAME_COOLDOWN: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 #[derive(Debug)]
 pub struct State {
-    pub hostname: &'static str,
-    pub handshake: bool,
+    pub hostname: String,
     pub db: MySqlPool,
+    pub signing_key: SigningKey,
+    pub key_log: KeyLog,
+    pub attachments: AttachmentStore,
+    pub discovery: DiscoveryLimiter,
+    /// Proof of work required for registration, disabled if `None`.
+    pub registration: Option<RegistrationGate>,
+    pub username_cooldown: Duration,
+    pub unknown_user_failures: UnknownUserFailures,
 }
 
 impl State {
-    pub fn new(hostname: &'static str, db: MySqlPool) -> Self {
+    pub fn new(hostname: String, db: MySqlPool, signing_key: SigningKey) -> Self {
         Self {
             hostname,
             db,
-            handshake: false,
+            signing_key,
+            key_log: KeyLog::default(),
+            attachments: AttachmentStore::default(),
+            discovery: DiscoveryLimiter::default(),
+            registration: None,
+            username_cooldown: DEFAULT_USERNAME_COOLDOWN,
+            unknown_user_failures: UnknownUserFailures::default(),
         }
     }
 }
@@ -0,0 +1,651 @@
+//! TLS settings of a listener. Certificates loaded from files can be reloaded
+//! while the server is running, so that short-lived certificates can be rotated
+//! without dropping open connections. A listener can serve several domains by
+//! selecting a certificate based on the server name (SNI) sent by the client,
+//! and can optionally verify client certificates against a CA bundle (mTLS).
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        server::{
+            AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+            NoClientAuth, ResolvesServerCert,
+        },
+        sign::CertifiedKey,
+    },
+    TlsAcceptor,
+};
+use tracing::{error, info};
+
+/// How often certificate files are checked for changes by default.
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+    NoCertificates {
+        path: PathBuf,
+    },
+    NoPrivateKey {
+        path: PathBuf,
+    },
+    InvalidPrivateKey {
+        path: Option<PathBuf>,
+    },
+    /// The private key doesn't belong to the first certificate of the chain.
+    KeyMismatch {
+        path: Option<PathBuf>,
+    },
+    /// The private key is encrypted but no passphrase was configured.
+    MissingPassphrase {
+        path: PathBuf,
+    },
+    Passphrase {
+        source: Passphrase,
+        msg: String,
+    },
+    Decrypt {
+        path: PathBuf,
+    },
+    InvalidClientCa {
+        path: PathBuf,
+    },
+    Config(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, err } => write!(f, "Couldn't read {}: {err}", path.display()),
+            Self::NoCertificates { path } => {
+                write!(f, "No certificates found in {}", path.display())
+            }
+            Self::NoPrivateKey { path } => write!(
+                f,
+                "No PEM encoded PKCS#1, SEC1 or PKCS#8 private key found in {}",
+                path.display()
+            ),
+            Self::InvalidPrivateKey { path: Some(path) } => {
+                write!(
+                    f,
+                    "Unsupported or invalid private key in {}",
+                    path.display()
+                )
+            }
+            Self::InvalidPrivateKey { path: None } => {
+                write!(f, "Unsupported or invalid private key")
+            }
+            Self::KeyMismatch { path: Some(path) } => {
+                write!(f, "Private key {} doesn't match the certificate", path.display())
+            }
+            Self::KeyMismatch { path: None } => {
+                write!(f, "Private key doesn't match the certificate")
+            }
+            Self::MissingPassphrase { path } => write!(
+                f,
+                "Private key {} is encrypted but no passphrase was configured",
+                path.display()
+            ),
+            Self::Passphrase { source, msg } => {
+                write!(
+                    f,
+                    "Couldn't read private key passphrase from {source}: {msg}"
+                )
+            }
+            Self::Decrypt { path } => write!(
+                f,
+                "Couldn't decrypt private key {}, is the passphrase correct?",
+                path.display()
+            ),
+            Self::InvalidClientCa { path } => {
+                write!(f, "No valid CA certificates found in {}", path.display())
+            }
+            Self::Config(err) => write!(f, "Couldn't create TLS config: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+pub fn load_certs(path: &Path) -> Result<Vec<rustls::Certificate>, TlsError> {
+    let io_err = |err| TlsError::Io {
+        path: path.to_path_buf(),
+        err,
+    };
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path).map_err(io_err)?);
+    let certs = rustls_pemfile::certs(&mut reader).map_err(io_err)?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+/// Where the passphrase of an encrypted private key is read from. It is read
+/// again whenever the key is reloaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Passphrase {
+    /// The environment variable with this name.
+    Env(String),
+    /// A file, ignoring a trailing newline.
+    File(PathBuf),
+}
+
+impl fmt::Display for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Env(name) => write!(f, "environment variable {name}"),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Passphrase {
+    fn read(&self) -> Result<Vec<u8>, TlsError> {
+        let passphrase_err = |msg: String| TlsError::Passphrase {
+            source: self.clone(),
+            msg,
+        };
+
+        let mut passphrase = match self {
+            Self::Env(name) => std::env::var(name)
+                .map_err(|err| passphrase_err(err.to_string()))?
+                .into_bytes(),
+            Self::File(path) => {
+                std::fs::read(path).map_err(|err| passphrase_err(err.to_string()))?
+            }
+        };
+
+        while matches!(passphrase.last(), Some(b'\n' | b'\r')) {
+            passphrase.pop();
+        }
+        Ok(passphrase)
+    }
+}
+
+/// Loads the first PKCS#1 (RSA), SEC1 (EC) or PKCS#8 private key from a PEM
+/// file. Encrypted keys, either PKCS#8 or legacy OpenSSL encrypted PEM, are
+/// decrypted with `passphrase`.
+pub fn load_private_key(
+    path: &Path,
+    passphrase: Option<&Passphrase>,
+) -> Result<rustls::PrivateKey, TlsError> {
+    let io_err = |err| TlsError::Io {
+        path: path.to_path_buf(),
+        err,
+    };
+    let pem = std::fs::read(path).map_err(io_err)?;
+
+    let encrypted = pem
+        .windows(b"ENCRYPTED".len())
+        .any(|window| window == b"ENCRYPTED");
+    if encrypted {
+        let passphrase = passphrase
+            .ok_or_else(|| TlsError::MissingPassphrase {
+                path: path.to_path_buf(),
+            })?
+            .read()?;
+
+        let decrypt_err = |_| TlsError::Decrypt {
+            path: path.to_path_buf(),
+        };
+        let key = openssl::pkey::PKey::private_key_from_pem_passphrase(&pem, &passphrase)
+            .map_err(decrypt_err)?;
+        return Ok(rustls::PrivateKey(
+            key.private_key_to_pkcs8().map_err(decrypt_err)?,
+        ));
+    }
+
+    rustls_pemfile::read_all(&mut pem.as_slice())
+        .map_err(io_err)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der) => Some(rustls::PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::NoPrivateKey {
+            path: path.to_path_buf(),
+        })
+}
+
+/// Loads a bundle of CA certificates that client certificates are verified against.
+pub fn load_client_ca(path: &Path) -> Result<rustls::RootCertStore, TlsError> {
+    let certs = load_certs(path)?;
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(&certs);
+
+    if added == 0 || ignored > 0 {
+        return Err(TlsError::InvalidClientCa {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(roots)
+}
+
+/// Formats the subject of the verified certificate a client presented, e.g.
+/// `CN=admin, O=Example`.
+pub fn client_subject(connection: &rustls::ServerConnection) -> Option<String> {
+    let cert = connection.peer_certificates()?.first()?;
+    let cert = openssl::x509::X509::from_der(&cert.0).ok()?;
+
+    let subject = cert
+        .subject_name()
+        .entries()
+        .map(|entry| {
+            let name = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().to_string().unwrap_or_default();
+            format!("{name}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(subject)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct CertificateFiles {
+    cert: PathBuf,
+    private_key: PathBuf,
+    passphrase: Option<Passphrase>,
+}
+
+impl CertificateFiles {
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified());
+        Some((
+            modified(&self.cert).ok()?,
+            modified(&self.private_key).ok()?,
+        ))
+    }
+
+    fn load(&self) -> Result<CertifiedKey, TlsError> {
+        let certs = load_certs(&self.cert)?;
+        let private_key = load_private_key(&self.private_key, self.passphrase.as_ref())?;
+        certified_key(certs, &private_key, Some(&self.private_key))
+    }
+}
+
+fn certified_key(
+    certs: Vec<rustls::Certificate>,
+    private_key: &rustls::PrivateKey,
+    path: Option<&Path>,
+) -> Result<CertifiedKey, TlsError> {
+    let key =
+        rustls::sign::any_supported_type(private_key).map_err(|_| TlsError::InvalidPrivateKey {
+            path: path.map(Path::to_path_buf),
+        })?;
+    if !key_matches(&certs, private_key) {
+        return Err(TlsError::KeyMismatch {
+            path: path.map(Path::to_path_buf),
+        });
+    }
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Whether `private_key` belongs to the end entity certificate of `certs`.
+fn key_matches(certs: &[rustls::Certificate], private_key: &rustls::PrivateKey) -> bool {
+    let Some(cert) = certs.first() else {
+        return false;
+    };
+    let cert_key = openssl::x509::X509::from_der(&cert.0).and_then(|cert| cert.public_key());
+    let private_key = openssl::pkey::PKey::private_key_from_der(&private_key.0);
+    match (cert_key, private_key) {
+        (Ok(cert_key), Ok(private_key)) => cert_key.public_eq(&private_key),
+        _ => false,
+    }
+}
+
+/// A certificate chain and key that, when loaded from files, can be replaced
+/// by the current contents of those files at any time.
+pub struct ReloadableCert {
+    files: Option<CertificateFiles>,
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for ReloadableCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadableCert")
+            .field("files", &self.files)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReloadableCert {
+    pub fn new(
+        certs: Vec<rustls::Certificate>,
+        private_key: rustls::PrivateKey,
+    ) -> Result<Self, TlsError> {
+        Ok(Self {
+            files: None,
+            modified: Mutex::new(None),
+            current: RwLock::new(Arc::new(certified_key(certs, &private_key, None)?)),
+        })
+    }
+
+    /// Loads the certificate chain and key from PEM files. `passphrase` is only
+    /// needed if the key is encrypted.
+    pub fn from_files(
+        cert: impl Into<PathBuf>,
+        private_key: impl Into<PathBuf>,
+        passphrase: Option<Passphrase>,
+    ) -> Result<Self, TlsError> {
+        let files = CertificateFiles {
+            cert: cert.into(),
+            private_key: private_key.into(),
+            passphrase,
+        };
+        let modified = files.modified();
+        let current = files.load()?;
+
+        Ok(Self {
+            files: Some(files),
+            modified: Mutex::new(modified),
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        Arc::clone(&self.current.read().expect("Certificate lock poisoned"))
+    }
+
+    /// Loads the certificate files again. On failure the previous certificate
+    /// stays in use.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let Some(files) = &self.files else {
+            return Ok(());
+        };
+
+        let modified = files.modified();
+        let key = files.load()?;
+        *self.current.write().expect("Certificate lock poisoned") = Arc::new(key);
+        *self.modified.lock().expect("Certificate lock poisoned") = modified;
+        info!("Reloaded TLS certificate {}", files.cert.display());
+        Ok(())
+    }
+
+    /// Reloads the certificate files if either of them was modified since they
+    /// were last loaded. While only one of the files has been replaced the key
+    /// doesn't match the certificate, which is reported as an error and keeps
+    /// the previous pair in use until the other file changes too.
+    pub fn reload_if_changed(&self) -> Result<(), TlsError> {
+        let Some(files) = &self.files else {
+            return Ok(());
+        };
+
+        let modified = files.modified();
+        let mut last_modified = self.modified.lock().expect("Certificate lock poisoned");
+        if modified.is_none() || modified == *last_modified {
+            return Ok(());
+        }
+
+        // Remember the new times even if loading fails, so a broken file is only
+        // reported once rather than on every check.
+        *last_modified = modified;
+        drop(last_modified);
+
+        let key = files.load()?;
+        *self.current.write().expect("Certificate lock poisoned") = Arc::new(key);
+        info!("Reloaded TLS certificate {}", files.cert.display());
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// Whether a listener asks clients for a certificate.
+#[derive(Debug, Clone, Default)]
+pub enum ClientAuth {
+    #[default]
+    Disabled,
+    /// Clients may present a certificate, which must then be signed by one of
+    /// the given CAs.
+    Optional(rustls::RootCertStore),
+    /// Clients without a certificate signed by one of the given CAs are rejected
+    /// during the handshake.
+    Required(rustls::RootCertStore),
+}
+
+/// Certificates and key a listener presents to clients.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    /// Certificate used when the client doesn't send a server name or sends one
+    /// that has no certificate of its own.
+    pub cert: Arc<ReloadableCert>,
+    /// Certificates selected by server name. Names are lower case and may start
+    /// with a `*.` wildcard label.
+    pub server_names: HashMap<String, Arc<ReloadableCert>>,
+    pub client_auth: ClientAuth,
+}
+
+impl TlsSettings {
+    pub fn new(
+        certs: Vec<rustls::Certificate>,
+        private_key: rustls::PrivateKey,
+    ) -> Result<Self, TlsError> {
+        Ok(Self::with_default_cert(ReloadableCert::new(
+            certs,
+            private_key,
+        )?))
+    }
+
+    /// Loads the certificate chain and key from PEM files, which are read again
+    /// whenever the server's certificates are reloaded.
+    pub fn from_files(
+        cert: impl Into<PathBuf>,
+        private_key: impl Into<PathBuf>,
+        passphrase: Option<Passphrase>,
+    ) -> Result<Self, TlsError> {
+        Ok(Self::with_default_cert(ReloadableCert::from_files(
+            cert,
+            private_key,
+            passphrase,
+        )?))
+    }
+
+    pub fn with_default_cert(cert: ReloadableCert) -> Self {
+        Self {
+            cert: Arc::new(cert),
+            server_names: HashMap::new(),
+            client_auth: ClientAuth::Disabled,
+        }
+    }
+
+    /// Presents `cert` to clients asking for `server_name`.
+    pub fn with_server_name(mut self, server_name: &str, cert: ReloadableCert) -> Self {
+        self.server_names
+            .insert(normalize_server_name(server_name), Arc::new(cert));
+        self
+    }
+
+    pub fn with_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = client_auth;
+        self
+    }
+
+    pub fn acceptor(&self) -> Result<TlsAcceptor, TlsError> {
+        let verifier = match &self.client_auth {
+            ClientAuth::Disabled => NoClientAuth::boxed(),
+            ClientAuth::Optional(roots) => {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots.clone()).boxed()
+            }
+            ClientAuth::Required(roots) => AllowAnyAuthenticatedClient::new(roots.clone()).boxed(),
+        };
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(Arc::new(SniResolver {
+                default: Arc::clone(&self.cert),
+                server_names: self.server_names.clone(),
+            }));
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn certs(&self) -> impl Iterator<Item = &Arc<ReloadableCert>> {
+        std::iter::once(&self.cert).chain(self.server_names.values())
+    }
+}
+
+fn normalize_server_name(server_name: &str) -> String {
+    server_name.trim_end_matches('.').to_lowercase()
+}
+
+/// Picks a certificate by exact server name, then by wildcard, then falls back
+/// to the default certificate.
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<ReloadableCert>,
+    server_names: HashMap<String, Arc<ReloadableCert>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let cert = client_hello
+            .server_name()
+            .map(normalize_server_name)
+            .and_then(|name| {
+                self.server_names.get(&name).or_else(|| {
+                    let (_, parent) = name.split_once('.')?;
+                    self.server_names.get(&format!("*.{parent}"))
+                })
+            })
+            .unwrap_or(&self.default);
+
+        cert.resolve(client_hello)
+    }
+}
+
+/// Reloads the certificates of all listeners of a server, see
+/// [`ArkeServer::tls_reloader`](super::ArkeServer::tls_reloader).
+#[derive(Debug, Clone, Default)]
+pub struct TlsReloader {
+    certs: Vec<Arc<ReloadableCert>>,
+}
+
+impl TlsReloader {
+    pub(crate) fn new<'a>(settings: impl Iterator<Item = &'a TlsSettings>) -> Self {
+        Self {
+            certs: settings.flat_map(TlsSettings::certs).cloned().collect(),
+        }
+    }
+
+    /// Reloads every certificate, e.g. after a SIGHUP.
+    pub fn reload(&self) {
+        for cert in &self.certs {
+            if let Err(err) = cert.reload() {
+                error!("Couldn't reload TLS certificate, keeping the current one. {err}");
+            }
+        }
+    }
+
+    /// Reloads certificates whose files have changed.
+    pub fn reload_changed(&self) {
+        for cert in &self.certs {
+            if let Err(err) = cert.reload_if_changed() {
+                error!("Couldn't reload TLS certificate, keeping the current one. {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        x509::{X509Name, X509},
+    };
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn self_signed(key: &PKey<Private>) -> X509 {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(key, MessageDigest::sha256()).unwrap();
+        cert.build()
+    }
+
+    /// Writes a certificate and key to fresh files in the temp directory.
+    fn write_pair(name: &str, cert: &X509, key: &PKey<Private>) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("arke-tls-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("privateKey.pem"));
+        std::fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn matching_key_is_accepted() {
+        let key = generate_key();
+        let (cert, private_key) = write_pair("match", &self_signed(&key), &key);
+        assert!(ReloadableCert::from_files(&cert, &private_key, None).is_ok());
+
+        // SEC1 keys are compared the same way as PKCS#8 ones.
+        std::fs::write(&private_key, key.ec_key().unwrap().private_key_to_pem().unwrap()).unwrap();
+        assert!(ReloadableCert::from_files(&cert, &private_key, None).is_ok());
+    }
+
+    #[test]
+    fn mismatched_key_is_rejected() {
+        let (cert, private_key) = write_pair("mismatch", &self_signed(&generate_key()), &generate_key());
+        assert!(matches!(
+            ReloadableCert::from_files(&cert, &private_key, None),
+            Err(TlsError::KeyMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn reload_keeps_the_previous_pair_on_mismatch() {
+        let key = generate_key();
+        let original = self_signed(&key);
+        let (cert_path, key_path) = write_pair("reload", &original, &key);
+        let cert = ReloadableCert::from_files(&cert_path, &key_path, None).unwrap();
+
+        // Only the certificate has been replaced so far.
+        let new_key = generate_key();
+        let replacement = self_signed(&new_key);
+        std::fs::write(&cert_path, replacement.to_pem().unwrap()).unwrap();
+        assert!(matches!(cert.reload(), Err(TlsError::KeyMismatch { .. })));
+        assert_eq!(cert.current().cert[0].0, original.to_der().unwrap());
+
+        std::fs::write(&key_path, new_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        cert.reload().unwrap();
+        assert_eq!(cert.current().cert[0].0, replacement.to_der().unwrap());
+    }
+}
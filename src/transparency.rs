@@ -0,0 +1,556 @@
+//! Append-only Merkle tree log of every `(username, identity_key)` binding the
+//! server has handed out, following the construction of RFC 6962. Clients can
+//! check that a key they were served is included in a signed tree head and that
+//! successive tree heads are consistent with each other.
+
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    mysql::{MySqlConnection, MySqlPool},
+    FromRow,
+};
+use std::collections::HashMap;
+
+use crate::crypto::{PublicKey, SigningKey};
+
+pub type Hash = [u8; 32];
+
+#[derive(Debug, Clone, FromRow)]
+struct KeyLogEntry {
+    leaf_index: u64,
+    username: String,
+    identity_key: PublicKey,
+}
+
+/// In-memory copy of the key log's leaf hashes, backed by the `key_log` table.
+#[derive(Debug, Default)]
+pub struct KeyLog {
+    tree: MerkleTree,
+    latest: HashMap<String, u64>,
+}
+
+impl KeyLog {
+    pub async fn load(db: &MySqlPool) -> Result<Self, sqlx::Error> {
+        let entries: Vec<KeyLogEntry> = sqlx::query_as(
+            "SELECT leaf_index, username, identity_key FROM key_log ORDER BY leaf_index",
+        )
+        .fetch_all(db)
+        .await?;
+
+        let mut log = Self::default();
+        for entry in entries {
+            if entry.leaf_index != log.size() {
+                return Err(sqlx::Error::Protocol(format!(
+                    "key_log is missing leaf {}, found leaf {} instead",
+                    log.size(),
+                    entry.leaf_index
+                )));
+            }
+            log.push(&entry.username, &entry.identity_key);
+        }
+
+        Ok(log)
+    }
+
+    /// Adds a binding that has been stored with [`KeyLog::insert`] to the tree
+    /// and returns its leaf index.
+    pub fn push(&mut self, username: &str, identity_key: &PublicKey) -> u64 {
+        let index = self.size();
+        self.tree.push(leaf_hash(&leaf_data(username, identity_key)));
+        self.latest.insert(username.to_string(), index);
+        index
+    }
+
+    /// Stores a new binding as the next leaf of the log. The binding only
+    /// becomes part of the tree once it is [`pushed`](KeyLog::push), which
+    /// should happen after the surrounding transaction commits.
+    pub async fn insert(
+        &self,
+        conn: &mut MySqlConnection,
+        username: &str,
+        identity_key: &PublicKey,
+    ) -> Result<u64, sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO key_log(leaf_index, username, identity_key, logged_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(self.size())
+        .bind(username)
+        .bind(identity_key)
+        .bind(crate::unix_timestamp())
+        .execute(conn)
+        .await?;
+
+        Ok(self.size())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.tree.len() as u64
+    }
+
+    /// Index of the most recent binding logged for `username`.
+    pub fn latest_index(&self, username: &str) -> Option<u64> {
+        self.latest.get(username).copied()
+    }
+
+    pub fn tree_head(&self, signing_key: &SigningKey) -> SignedTreeHead {
+        SignedTreeHead::new(self.size(), self.tree.root(self.tree.len()), signing_key)
+    }
+
+    /// Proves that leaf `index` is part of the current tree.
+    pub fn inclusion_proof(&self, index: u64, signing_key: &SigningKey) -> Option<InclusionProof> {
+        (index < self.size()).then(|| InclusionProof {
+            leaf_index: index,
+            tree_head: self.tree_head(signing_key),
+            audit_path: self.tree.audit_path(index as usize, self.tree.len()),
+        })
+    }
+
+    /// Proves that the tree of size `first` is a prefix of the tree of size `second`.
+    pub fn consistency_proof(&self, first: u64, second: u64) -> Option<ConsistencyProof> {
+        (first <= second && second <= self.size()).then(|| ConsistencyProof {
+            first,
+            second,
+            proof: self.tree.consistency_path(first as usize, second as usize),
+        })
+    }
+}
+
+/// Hashes of all complete subtrees of the log, so that a root or a proof only
+/// needs to combine O(log n) stored hashes.
+#[derive(Debug, Default)]
+struct MerkleTree {
+    /// `levels[h][i]` is the hash of the leaves `i * 2^h..(i + 1) * 2^h`.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    fn len(&self) -> usize {
+        self.levels.first().map_or(0, Vec::len)
+    }
+
+    fn push(&mut self, leaf: Hash) {
+        let mut hash = leaf;
+        for height in 0.. {
+            if self.levels.len() == height {
+                self.levels.push(vec![]);
+            }
+            let level = &mut self.levels[height];
+            level.push(hash);
+            if level.len() % 2 == 1 {
+                break;
+            }
+            hash = node_hash(&level[level.len() - 2], &level[level.len() - 1]);
+        }
+    }
+
+    /// Hash of the leaves `start..end`, split as in [`root_hash`].
+    fn subtree(&self, start: usize, end: usize) -> Hash {
+        let n = end - start;
+        if n == 0 {
+            return openssl::sha::sha256(&[]);
+        }
+        if n.is_power_of_two() && start.is_multiple_of(n) {
+            let height = n.trailing_zeros() as usize;
+            return self.levels[height][start >> height];
+        }
+
+        let k = split_point(n);
+        node_hash(&self.subtree(start, start + k), &self.subtree(start + k, end))
+    }
+
+    /// Root of the tree made of the first `size` leaves.
+    fn root(&self, size: usize) -> Hash {
+        self.subtree(0, size)
+    }
+
+    /// Audit path of leaf `index` in the tree of the first `size` leaves.
+    fn audit_path(&self, index: usize, size: usize) -> Vec<Hash> {
+        fn path(tree: &MerkleTree, index: usize, start: usize, end: usize) -> Vec<Hash> {
+            if end - start <= 1 {
+                return vec![];
+            }
+
+            let k = split_point(end - start);
+            if index < start + k {
+                let mut path = path(tree, index, start, start + k);
+                path.push(tree.subtree(start + k, end));
+                path
+            } else {
+                let mut path = path(tree, index, start + k, end);
+                path.push(tree.subtree(start, start + k));
+                path
+            }
+        }
+
+        path(self, index, 0, size)
+    }
+
+    /// Consistency proof between the trees of the first `first` and the first
+    /// `second` leaves.
+    fn consistency_path(&self, first: usize, second: usize) -> Vec<Hash> {
+        fn subproof(tree: &MerkleTree, m: usize, start: usize, end: usize, complete: bool) -> Vec<Hash> {
+            if start + m == end {
+                return if complete { vec![] } else { vec![tree.subtree(start, end)] };
+            }
+
+            let k = split_point(end - start);
+            if m <= k {
+                let mut proof = subproof(tree, m, start, start + k, complete);
+                proof.push(tree.subtree(start + k, end));
+                proof
+            } else {
+                let mut proof = subproof(tree, m - k, start + k, end, false);
+                proof.push(tree.subtree(start, start + k));
+                proof
+            }
+        }
+
+        if first == 0 {
+            return vec![];
+        }
+        subproof(self, first, 0, second, true)
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SignedTreeHead {
+    pub tree_size: u64,
+    pub root_hash: Hash,
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+}
+
+impl SignedTreeHead {
+    fn new(tree_size: u64, root_hash: Hash, signing_key: &SigningKey) -> Self {
+        let mut head = Self {
+            tree_size,
+            root_hash,
+            timestamp: crate::unix_timestamp(),
+            signature: vec![],
+        };
+        head.signature = signing_key.sign(&head.signed_data());
+        head
+    }
+
+    /// The bytes covered by `signature`: the big endian tree size and timestamp
+    /// followed by the root hash.
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(self.tree_size.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        data.extend_from_slice(&self.root_hash);
+        data
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub tree_head: SignedTreeHead,
+    pub audit_path: Vec<Hash>,
+}
+
+impl InclusionProof {
+    pub fn verify(&self, username: &str, identity_key: &PublicKey) -> bool {
+        verify_inclusion(
+            &leaf_hash(&leaf_data(username, identity_key)),
+            self.leaf_index,
+            self.tree_head.tree_size,
+            &self.audit_path,
+            &self.tree_head.root_hash,
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub first: u64,
+    pub second: u64,
+    pub proof: Vec<Hash>,
+}
+
+impl ConsistencyProof {
+    pub fn verify(&self, first_root: &Hash, second_root: &Hash) -> bool {
+        verify_consistency(self.first, self.second, &self.proof, first_root, second_root)
+    }
+}
+
+/// The logged representation of a binding: the username, a NUL byte and the
+/// identity key.
+pub fn leaf_data(username: &str, identity_key: &PublicKey) -> Vec<u8> {
+    let mut data = Vec::from(username.as_bytes());
+    data.push(0);
+    data.extend_from_slice(identity_key.as_ref());
+    data
+}
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(&[0x00]);
+    hasher.update(data);
+    hasher.finish()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(&[0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finish()
+}
+
+/// Largest power of two strictly smaller than `n`.
+fn split_point(n: usize) -> usize {
+    debug_assert!(n > 1);
+    1 << (usize::BITS - (n - 1).leading_zeros() - 1)
+}
+
+/// Root hash of a tree made of `leaves`, computed from scratch.
+pub fn root_hash(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => openssl::sha::sha256(&[]),
+        1 => leaves[0],
+        n => {
+            let k = split_point(n);
+            node_hash(&root_hash(&leaves[..k]), &root_hash(&leaves[k..]))
+        }
+    }
+}
+
+/// Verifies an audit path as described in RFC 9162, section 2.1.3.2.
+pub fn verify_inclusion(leaf: &Hash, index: u64, tree_size: u64, path: &[Hash], root: &Hash) -> bool {
+    if index >= tree_size {
+        return false;
+    }
+
+    let (mut fn_, mut sn) = (index, tree_size - 1);
+    let mut r = *leaf;
+    for p in path {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            r = node_hash(p, &r);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+
+    sn == 0 && &r == root
+}
+
+/// Verifies a consistency proof as described in RFC 9162, section 2.1.4.2.
+pub fn verify_consistency(first: u64, second: u64, proof: &[Hash], first_root: &Hash, second_root: &Hash) -> bool {
+    if first > second {
+        return false;
+    }
+    if first == second {
+        return proof.is_empty() && first_root == second_root;
+    }
+    if first == 0 {
+        return proof.is_empty();
+    }
+
+    let mut path = proof.to_vec();
+    if first.is_power_of_two() {
+        path.insert(0, *first_root);
+    }
+
+    let Some((head, rest)) = path.split_first() else {
+        return false;
+    };
+
+    let (mut fn_, mut sn) = (first - 1, second - 1);
+    while fn_ & 1 == 1 {
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+
+    let (mut fr, mut sr) = (*head, *head);
+    for c in rest {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+
+    sn == 0 && &fr == first_root && &sr == second_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Leaf inputs of the RFC 6962 / RFC 9162 reference test vectors.
+    const LEAVES: [&str; 8] = [
+        "",
+        "00",
+        "10",
+        "2021",
+        "3031",
+        "40414243",
+        "5051525354555657",
+        "606162636465666768696a6b6c6d6e6f",
+    ];
+
+    const ROOTS: [&str; 8] = [
+        "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+        "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+        "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+        "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+        "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+        "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+        "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+        "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+    ];
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn hash(hex: &str) -> Hash {
+        unhex(hex).try_into().unwrap()
+    }
+
+    fn leaves(n: usize) -> Vec<Hash> {
+        (0..n).map(|i| leaf_hash(&unhex(LEAVES[i % LEAVES.len()]))).collect()
+    }
+
+    fn tree(n: usize) -> MerkleTree {
+        let mut tree = MerkleTree::default();
+        for leaf in leaves(n) {
+            tree.push(leaf);
+        }
+        tree
+    }
+
+    #[test]
+    fn empty_tree_root() {
+        assert_eq!(
+            MerkleTree::default().root(0),
+            hash("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+    }
+
+    #[test]
+    fn roots_match_reference_vectors() {
+        let tree = tree(8);
+        for (size, root) in (1..).zip(ROOTS) {
+            assert_eq!(tree.root(size), hash(root), "size {size}");
+            assert_eq!(root_hash(&leaves(size)), hash(root), "size {size}");
+        }
+    }
+
+    #[test]
+    fn inclusion_proofs_match_reference_vectors() {
+        let vectors: [(usize, usize, &[&str]); 4] = [
+            (0, 8, &[
+                "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+                "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+            ]),
+            (5, 8, &[
+                "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+                "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+                "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+            ]),
+            (2, 3, &["fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125"]),
+            (1, 5, &[
+                "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+                "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+            ]),
+        ];
+
+        let tree = tree(8);
+        for (index, size, expected) in vectors {
+            let path = tree.audit_path(index, size);
+            assert_eq!(path, expected.iter().map(|h| hash(h)).collect::<Vec<_>>(), "leaf {index} of {size}");
+            assert!(verify_inclusion(&leaves(8)[index], index as u64, size as u64, &path, &tree.root(size)));
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_match_reference_vectors() {
+        let vectors: [(usize, usize, &[&str]); 4] = [
+            (1, 1, &[]),
+            (1, 8, &[
+                "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+                "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+            ]),
+            (6, 8, &[
+                "0ebc5d3437fbe2db158b9f126a1d118e308181031d0a949f8dededebc558ef6a",
+                "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+                "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+            ]),
+            (2, 5, &[
+                "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+            ]),
+        ];
+
+        let tree = tree(8);
+        for (first, second, expected) in vectors {
+            let proof = tree.consistency_path(first, second);
+            assert_eq!(proof, expected.iter().map(|h| hash(h)).collect::<Vec<_>>(), "{first} to {second}");
+            assert!(verify_consistency(first as u64, second as u64, &proof, &tree.root(first), &tree.root(second)));
+        }
+    }
+
+    #[test]
+    fn proofs_verify_for_every_size() {
+        let leaves: Vec<Hash> = (0u32..33).map(|i| leaf_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::default();
+        for leaf in &leaves {
+            tree.push(*leaf);
+        }
+
+        for size in 1..=leaves.len() {
+            let root = tree.root(size);
+            assert_eq!(root, root_hash(&leaves[..size]), "size {size}");
+
+            for (index, leaf) in leaves[..size].iter().enumerate() {
+                let path = tree.audit_path(index, size);
+                assert!(verify_inclusion(leaf, index as u64, size as u64, &path, &root));
+                if index ^ 1 < size {
+                    assert!(!verify_inclusion(leaf, (index ^ 1) as u64, size as u64, &path, &root));
+                }
+            }
+
+            for first in 0..=size {
+                let proof = tree.consistency_path(first, size);
+                assert!(verify_consistency(first as u64, size as u64, &proof, &tree.root(first), &root));
+                if first > 0 && first < size {
+                    assert!(!verify_consistency(first as u64, size as u64, &proof, &root, &root));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_inclusion_proof_is_rejected() {
+        let leaves = leaves(7);
+        let tree = tree(leaves.len());
+        let mut path = tree.audit_path(3, 7);
+        path[1][0] ^= 1;
+        assert!(!verify_inclusion(&leaves[3], 3, 7, &path, &tree.root(7)));
+        assert!(!verify_inclusion(&leaves[2], 3, 7, &tree.audit_path(3, 7), &tree.root(7)));
+    }
+}
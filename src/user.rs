@@ -1,10 +1,74 @@
 use macros::Entity;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sqlx::{
+    mysql::{MySqlConnection, MySqlPool},
+    FromRow,
+};
+use std::time::Duration;
+use unicode_normalization::UnicodeNormalization;
 
-use crate::crypto::PublicKey;
+use crate::{
+    crypto::PublicKey,
+    group::Group,
+    identity::IdentityKeyChange,
+    message::{MessageKind, SystemMessage},
+    registration::ProofOfWork,
+    server::command::CommandError,
+    transparency::InclusionProof,
+};
 
-#[derive(Entity)]
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "arke",
+    "postmaster",
+    "root",
+    "security",
+    "server",
+    "support",
+    "system",
+];
+
+/// Normalizes a username to NFKC lowercase and checks that it is a valid,
+/// unreserved name. The returned string is the form that should be stored.
+pub fn normalize_username(username: &str) -> Result<String, CommandError> {
+    let username = username.trim().nfkc().collect::<String>().to_lowercase();
+    let invalid = |msg: &str| CommandError::InvalidUsername {
+        msg: msg.to_string(),
+    };
+
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        return Err(invalid(&format!(
+            "Username must be between {USERNAME_MIN_LENGTH} and {USERNAME_MAX_LENGTH} characters long"
+        )));
+    }
+
+    if !username.chars().next().is_some_and(char::is_alphanumeric) {
+        return Err(invalid("Username must start with a letter or digit"));
+    }
+
+    if !username
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        return Err(invalid(
+            "Username may only contain letters, digits, '_', '.' and '-'",
+        ));
+    }
+
+    if RESERVED_USERNAMES.contains(&username.as_str()) {
+        return Err(invalid("Username is reserved"));
+    }
+
+    Ok(username)
+}
+
+#[derive(Entity, FromRow)]
 pub struct User {
     pub username: String,
     pub identity_key: PublicKey,
@@ -14,6 +78,205 @@ pub struct User {
 }
 
 impl User {
+    /// Inserts the account as part of a larger transaction, e.g. together with
+    /// its first [`IdentityKeyChange`].
+    pub async fn create(&self, conn: &mut MySqlConnection) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO user(username, identity_key, signed_prekey, prekey_signature, \
+             one_time_prekeys) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&self.username)
+        .bind(&self.identity_key)
+        .bind(&self.signed_prekey)
+        .bind(&self.prekey_signature)
+        .bind(&self.one_time_prekeys)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn find(db: &MySqlPool, username: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT username, identity_key, signed_prekey, prekey_signature, one_time_prekeys \
+             FROM user WHERE username = ?",
+        )
+        .bind(username)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Summaries of every account, ordered by username.
+    pub async fn list(db: &MySqlPool) -> Result<Vec<UserSummary>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT username, CAST(JSON_LENGTH(one_time_prekeys) AS UNSIGNED) AS one_time_prekeys, \
+             disabled FROM user ORDER BY username",
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    pub async fn is_disabled(db: &MySqlPool, username: &str) -> Result<bool, sqlx::Error> {
+        let disabled: Option<(bool,)> =
+            sqlx::query_as("SELECT disabled FROM user WHERE username = ?")
+                .bind(username)
+                .fetch_optional(db)
+                .await?;
+
+        Ok(disabled.is_some_and(|(disabled,)| disabled))
+    }
+
+    /// Disables or re-enables an account. Disabled accounts can't authenticate.
+    ///
+    /// Returns `false` if no such account exists.
+    pub async fn set_disabled(
+        conn: &mut MySqlConnection,
+        username: &str,
+        disabled: bool,
+    ) -> Result<bool, sqlx::Error> {
+        let updated = sqlx::query("UPDATE user SET disabled = ? WHERE username = ?")
+            .bind(disabled)
+            .bind(username)
+            .execute(conn)
+            .await?;
+
+        Ok(updated.rows_affected() > 0)
+    }
+
+    /// Counts the one-time prekeys left across all accounts.
+    pub async fn prekey_stats(db: &MySqlPool) -> Result<PrekeyStats, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT CAST(COUNT(*) AS UNSIGNED) AS users, \
+             CAST(COALESCE(SUM(JSON_LENGTH(one_time_prekeys)), 0) AS UNSIGNED) AS available, \
+             CAST(COALESCE(SUM(JSON_LENGTH(one_time_prekeys) = 0), 0) AS UNSIGNED) AS exhausted \
+             FROM user",
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    /// Returns `true` if `username` belonged to a deleted account and is still
+    /// within its cooling-off period.
+    pub async fn is_tombstoned(db: &MySqlPool, username: &str) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT username FROM username_tombstone WHERE username = ? AND expires_at > NOW()",
+        )
+        .bind(username)
+        .fetch_optional(db)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Deletes an account along with its prekeys, queued messages, group
+    /// memberships, profiles and attachments, see [`Group::remove_deleted_member`]
+    /// for the groups. The identity key history is kept so that a key registered
+    /// later under the same name shows up as a change. Each user in `notify`
+    /// that exists is sent a [`SystemMessage::AccountDeleted`]. If `cooldown` is
+    /// non-zero the username is tombstoned until it expires.
+    ///
+    /// Meant to run inside a transaction; the stored attachment files are only
+    /// removed by the caller after it commits, see [`DeletedAccount`]. Returns
+    /// `None` if no such account exists.
+    pub async fn delete(
+        conn: &mut MySqlConnection,
+        username: &str,
+        cooldown: Duration,
+        notify: &[String],
+    ) -> Result<Option<DeletedAccount>, sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM user WHERE username = ?")
+            .bind(username)
+            .execute(&mut *conn)
+            .await?;
+
+        if deleted.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        sqlx::query("DELETE FROM message WHERE recipient = ?")
+            .bind(username)
+            .execute(&mut *conn)
+            .await?;
+
+        Group::remove_deleted_member(&mut *conn, username).await?;
+
+        sqlx::query("DELETE FROM profile WHERE username = ?")
+            .bind(username)
+            .execute(&mut *conn)
+            .await?;
+
+        let attachments: Vec<(Vec<u8>,)> = sqlx::query_as("SELECT attachment_id FROM attachment WHERE owner = ?")
+            .bind(username)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        sqlx::query("DELETE FROM attachment WHERE owner = ?")
+            .bind(username)
+            .execute(&mut *conn)
+            .await?;
+
+        if !cooldown.is_zero() {
+            sqlx::query(
+                "INSERT INTO username_tombstone(username, expires_at) \
+                 VALUES (?, DATE_ADD(NOW(), INTERVAL ? SECOND)) \
+                 ON DUPLICATE KEY UPDATE expires_at = VALUES(expires_at)",
+            )
+            .bind(username)
+            .bind(cooldown.as_secs())
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        let notice = SystemMessage::AccountDeleted {
+            username: username.to_string(),
+        }
+        .content();
+
+        for contact in notify {
+            sqlx::query(
+                "INSERT INTO message(recipient, kind, content) \
+                 SELECT username, ?, ? FROM user WHERE username = ?",
+            )
+            .bind(MessageKind::System)
+            .bind(&notice)
+            .bind(contact)
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        Ok(Some(DeletedAccount {
+            attachments: attachments.into_iter().map(|(attachment_id,)| attachment_id).collect(),
+        }))
+    }
+
+    /// Persists the current set of one-time prekeys.
+    pub async fn save_one_time_prekeys(&self, db: &MySqlPool) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE user SET one_time_prekeys = ? WHERE username = ?")
+            .bind(&self.one_time_prekeys)
+            .bind(&self.username)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Builds a prekey bundle for this user, consuming one of the one-time
+    /// prekeys if any are left.
+    pub async fn take_prekey_bundle(mut self, db: &MySqlPool) -> Result<PrekeyBundle, sqlx::Error> {
+        let one_time_prekey = self.pop_prekey();
+        if one_time_prekey.is_some() {
+            self.save_one_time_prekeys(db).await?;
+        }
+
+        Ok(PrekeyBundle {
+            identity_key_changed_at: IdentityKeyChange::recent_change(db, &self.username).await?,
+            username: self.username,
+            identity_key: self.identity_key,
+            signed_prekey: self.signed_prekey,
+            prekey_signature: self.prekey_signature,
+            one_time_prekey,
+            inclusion_proof: None,
+        })
+    }
+
     pub fn one_time_prekeys(&self) -> Vec<PublicKey> {
         serde_json::from_str(&self.one_time_prekeys).unwrap()
     }
@@ -32,12 +295,53 @@ impl User {
     }
 }
 
+/// What is left to clean up outside the database once an account deletion
+/// has been committed.
+#[derive(Debug, Default, Clone)]
+pub struct DeletedAccount {
+    /// Attachments owned by the account, whose files are still stored.
+    pub attachments: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct UserSummary {
+    pub username: String,
+    pub one_time_prekeys: u64,
+    pub disabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, FromRow)]
+pub struct PrekeyStats {
+    pub users: u64,
+    /// One-time prekeys left across all users.
+    pub available: u64,
+    /// Users that have run out of one-time prekeys.
+    pub exhausted: u64,
+}
+
+/// The keys needed to start a session with a user.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct PrekeyBundle {
+    pub username: String,
+    pub identity_key: PublicKey,
+    pub signed_prekey: PublicKey,
+    pub prekey_signature: Vec<u8>,
+    pub one_time_prekey: Option<PublicKey>,
+    /// Set if the identity key recently replaced an earlier one, in which case
+    /// clients should warn that the safety number has changed.
+    pub identity_key_changed_at: Option<u64>,
+    /// Proof that `identity_key` is part of the key transparency log.
+    pub inclusion_proof: Option<InclusionProof>,
+}
+
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct NewUser {
     pub username: String,
     pub identity_key: PublicKey,
     pub signed_prekey: PublicKey,
     pub prekey_signature: Vec<u8>,
+    /// Required if the server gates registration behind a proof of work.
+    pub proof_of_work: Option<ProofOfWork>,
 }
 
 impl From<NewUser> for User {
@@ -51,3 +355,61 @@ impl From<NewUser> for User {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid(username: &str) -> bool {
+        matches!(
+            normalize_username(username),
+            Err(CommandError::InvalidUsername { .. })
+        )
+    }
+
+    #[test]
+    fn normalizes_case_whitespace_and_compatibility_forms() {
+        assert_eq!(normalize_username("  Alice ").unwrap(), "alice");
+        assert_eq!(normalize_username("ＢＯＢ").unwrap(), "bob");
+        assert_eq!(normalize_username("ﬁona").unwrap(), "fiona");
+        assert_eq!(normalize_username("Ångström").unwrap(), "ångström");
+    }
+
+    #[test]
+    fn enforces_length_in_characters() {
+        assert!(is_invalid("ab"));
+        assert!(normalize_username("abc").is_ok());
+        assert!(normalize_username(&"a".repeat(USERNAME_MAX_LENGTH)).is_ok());
+        assert!(is_invalid(&"a".repeat(USERNAME_MAX_LENGTH + 1)));
+        // Multi-byte characters count once.
+        assert!(normalize_username(&"é".repeat(USERNAME_MAX_LENGTH)).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_characters() {
+        assert!(is_invalid("_alice"));
+        assert!(is_invalid(".alice"));
+        assert!(is_invalid("al ice"));
+        assert!(is_invalid("alice@example"));
+        assert!(is_invalid("alice\u{200b}"));
+        assert_eq!(normalize_username("a_l.i-ce").unwrap(), "a_l.i-ce");
+        assert_eq!(normalize_username("1alice").unwrap(), "1alice");
+    }
+
+    #[test]
+    fn rejects_reserved_names_in_any_form() {
+        assert!(is_invalid("admin"));
+        assert!(is_invalid("Admin"));
+        assert!(is_invalid(" ROOT "));
+        assert!(is_invalid("ａｄｍｉｎ"));
+        assert!(normalize_username("admins").is_ok());
+    }
+
+    #[test]
+    fn migration_renames_are_valid_usernames() {
+        // The unique-usernames migration renames colliding accounts to
+        // `user-<user_id>[-<n>]`, the longest of which must still be accepted.
+        let longest = format!("user-{}-{}", i32::MAX, i32::MAX);
+        assert_eq!(normalize_username(&longest).unwrap(), longest);
+    }
+}